@@ -0,0 +1,177 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+#![deny(
+    missing_copy_implementations,
+    missing_debug_implementations,
+    missing_docs,
+    trivial_casts,
+    trivial_numeric_casts,
+    unsafe_code,
+    unstable_features,
+    unused_import_braces,
+    unused_qualifications,
+    warnings
+)]
+
+//! Procedural macros of `easy_process`, which re-exports them when its
+//! `macros` feature is enabled.
+
+mod tokenize;
+
+use crate::tokenize::{tokenize, Piece, Placeholder};
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Expr, Ident, LitStr, Token,
+};
+
+/// Builds a [`Command`] from a literal command line, interpolating
+/// values as single arguments.
+///
+/// The literal is split in words at compile time following the same
+/// rules as `easy_process::run`, handling quotes and backslash escapes.
+/// Placeholders are written as in `format!`: `{}` and `{0}` refer to the
+/// positional arguments, `{name}` to a named argument or to a variable
+/// in scope, and `{{` and `}}` are literal braces. Placeholders are
+/// replaced in quotes as well.
+///
+/// The values must implement `AsRef<OsStr>` and are inserted in the
+/// word as they are, without being parsed, so a value containing
+/// spaces or quotes is never split. Pipelines, redirections and
+/// variable expansions are not supported.
+///
+/// [`Command`]: https://docs.rs/easy_process/*/easy_process/struct.Command.html
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// let msg = "fix: handle \"quoted\" paths";
+/// let dir = std::path::Path::new("/srv/my repo");
+/// easy_process::cmd!("git -C {} commit -m {msg}", dir).run()?;
+/// # Ok(())
+/// # }
+/// ```
+#[proc_macro]
+pub fn cmd(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as Input);
+    expand(&input)
+        .unwrap_or_else(|e| e.to_compile_error())
+        .into()
+}
+
+/// The arguments of the macro.
+struct Input {
+    cmd: LitStr,
+    args: Vec<Expr>,
+    named: Vec<(Ident, Expr)>,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream<'_>) -> syn::Result<Self> {
+        let cmd = input.parse()?;
+        let mut args = Vec::new();
+        let mut named = Vec::new();
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            if input.peek(Ident) && input.peek2(Token![=]) && !input.peek2(Token![==]) {
+                let name = input.parse()?;
+                input.parse::<Token![=]>()?;
+                named.push((name, input.parse()?));
+            } else if named.is_empty() {
+                args.push(input.parse()?);
+            } else {
+                return Err(input.error("positional arguments must come before named ones"));
+            }
+        }
+
+        Ok(Input { cmd, args, named })
+    }
+}
+
+fn expand(input: &Input) -> syn::Result<TokenStream2> {
+    let span = input.cmd.span();
+    let words = tokenize(&input.cmd.value()).map_err(|e| syn::Error::new(span, e))?;
+
+    // the temporaries are resolved at the definition site, so they can
+    // not shadow the variables captured from the caller
+    let buf = Ident::new("word", Span::mixed_site());
+    let bindings = (0 .. input.args.len() + input.named.len())
+        .map(|i| format_ident!("__arg{}", i, span = Span::mixed_site()))
+        .collect::<Vec<_>>();
+    let mut used = vec![false; bindings.len()];
+    let mut next = 0;
+    let mut value = |placeholder: &Placeholder| -> syn::Result<TokenStream2> {
+        let i = match placeholder {
+            Placeholder::Next => {
+                next += 1;
+                next - 1
+            }
+            Placeholder::Index(i) => *i,
+            Placeholder::Name(name) => match input.named.iter().position(|(n, _)| n == name) {
+                Some(i) => input.args.len() + i,
+                None => {
+                    let name = Ident::new(name, span);
+                    return Ok(quote!(&#name));
+                }
+            },
+        };
+        if i >= input.args.len() && !matches!(placeholder, Placeholder::Name(_)) {
+            return Err(syn::Error::new(
+                span,
+                format!(
+                    "invalid reference to positional argument {} ({} given)",
+                    i,
+                    input.args.len()
+                ),
+            ));
+        }
+        used[i] = true;
+        let binding = &bindings[i];
+        Ok(quote!(#binding))
+    };
+
+    let mut argv = Vec::new();
+    for word in &words {
+        if let [Piece::Text(text)] = word.as_slice() {
+            argv.push(quote!(::std::ffi::OsString::from(#text)));
+            continue;
+        }
+        let mut pieces = Vec::new();
+        for piece in word {
+            pieces.push(match piece {
+                Piece::Text(text) => quote!(#buf.push(#text);),
+                Piece::Arg(placeholder) => {
+                    let value = value(placeholder)?;
+                    quote!(#buf.push(::std::convert::AsRef::<::std::ffi::OsStr>::as_ref(#value));)
+                }
+            });
+        }
+        argv.push(quote!({
+            let mut #buf = ::std::ffi::OsString::new();
+            #(#pieces)*
+            #buf
+        }));
+    }
+
+    if let Some(i) = used.iter().position(|u| !u) {
+        let unused = match i.checked_sub(input.args.len()) {
+            None => &input.args[i],
+            Some(i) => &input.named[i].1,
+        };
+        return Err(syn::Error::new_spanned(unused, "argument never used"));
+    }
+
+    let exprs = input.args.iter().chain(input.named.iter().map(|(_, e)| e));
+    Ok(quote! {
+        match (#(&(#exprs),)*) {
+            (#(#bindings,)*) => ::easy_process::Command::from_args([#(#argv),*]),
+        }
+    })
+}
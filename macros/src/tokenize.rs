@@ -0,0 +1,246 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Splitting of the literal command line of `cmd!` in words.
+
+use std::{iter::Peekable, str::Chars};
+
+/// A piece of a word, which is either literal text or a placeholder.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Piece {
+    Text(String),
+    Arg(Placeholder),
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Placeholder {
+    /// `{}`
+    Next,
+    /// `{0}`
+    Index(usize),
+    /// `{name}`
+    Name(String),
+}
+
+#[derive(Debug, Default)]
+struct Word(Vec<Piece>);
+
+impl Word {
+    fn push(&mut self, c: char) {
+        match self.0.last_mut() {
+            Some(Piece::Text(text)) => text.push(c),
+            _ => self.0.push(Piece::Text(c.to_string())),
+        }
+    }
+}
+
+/// Splits the command line in words, as done by the parser of
+/// `easy_process`, keeping the placeholders as pieces of the words.
+pub(crate) fn tokenize(cmd: &str) -> Result<Vec<Vec<Piece>>, String> {
+    let mut words = Vec::new();
+    // `None` when not in the middle of a word, so empty quoted strings
+    // still produce a word
+    let mut word: Option<Word> = None;
+    let mut chars = cmd.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' => words.extend(word.take().map(|w| w.0)),
+            '|' | '<' | '>' => {
+                return Err(format!(
+                    "unquoted `{}`: pipelines and redirections are not supported",
+                    c
+                ))
+            }
+            '\\' => {
+                let w = word.get_or_insert_with(Word::default);
+                match chars.next() {
+                    Some(c) => w.push(control(c).unwrap_or(c)),
+                    None => w.push('\\'),
+                }
+            }
+            '\'' => single_quoted(&mut chars, word.get_or_insert_with(Word::default))?,
+            '"' => double_quoted(&mut chars, word.get_or_insert_with(Word::default))?,
+            '{' | '}' => brace(&mut chars, c, word.get_or_insert_with(Word::default))?,
+            c => word.get_or_insert_with(Word::default).push(c),
+        }
+    }
+    words.extend(word.map(|w| w.0));
+
+    if words.is_empty() {
+        return Err("empty command".to_string());
+    }
+
+    Ok(words)
+}
+
+fn single_quoted(chars: &mut Peekable<Chars<'_>>, w: &mut Word) -> Result<(), String> {
+    loop {
+        match chars.next().ok_or_else(unterminated)? {
+            '\'' => return Ok(()),
+            '\\' => match chars.next().ok_or_else(unterminated)? {
+                c @ '\'' | c @ '\\' => w.push(c),
+                c => {
+                    w.push('\\');
+                    w.push(c);
+                }
+            },
+            c @ '{' | c @ '}' => brace(chars, c, w)?,
+            c => w.push(c),
+        }
+    }
+}
+
+fn double_quoted(chars: &mut Peekable<Chars<'_>>, w: &mut Word) -> Result<(), String> {
+    loop {
+        match chars.next().ok_or_else(unterminated)? {
+            '"' => return Ok(()),
+            '\\' => match chars.next().ok_or_else(unterminated)? {
+                c @ '"' | c @ '\'' | c @ '\\' => w.push(c),
+                c => match control(c) {
+                    Some(c) => w.push(c),
+                    None => {
+                        w.push('\\');
+                        w.push(c);
+                    }
+                },
+            },
+            c @ '{' | c @ '}' => brace(chars, c, w)?,
+            c => w.push(c),
+        }
+    }
+}
+
+fn unterminated() -> String {
+    "unterminated quote".to_string()
+}
+
+/// Parses the remaining of a placeholder, or of an escaped brace,
+/// starting with `c`.
+fn brace(chars: &mut Peekable<Chars<'_>>, c: char, w: &mut Word) -> Result<(), String> {
+    if chars.next_if_eq(&c).is_some() {
+        w.push(c);
+        return Ok(());
+    }
+    if c == '}' {
+        return Err("unmatched `}`, use `}}` for a literal brace".to_string());
+    }
+
+    let mut name = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => name.push(c),
+            None => {
+                return Err("unterminated placeholder, use `{{` for a literal brace".to_string())
+            }
+        }
+    }
+
+    let is_ident = |s: &str| {
+        s.chars()
+            .next()
+            .is_some_and(|c| c == '_' || c.is_alphabetic())
+            && s.chars().all(|c| c == '_' || c.is_alphanumeric())
+            && s != "_"
+    };
+    let placeholder = if name.is_empty() {
+        Placeholder::Next
+    } else if let Ok(i) = name.parse() {
+        Placeholder::Index(i)
+    } else if is_ident(&name) {
+        Placeholder::Name(name)
+    } else {
+        return Err(format!(
+            "invalid placeholder `{{{}}}`, format specs are not supported",
+            name
+        ));
+    };
+    w.0.push(Piece::Arg(placeholder));
+
+    Ok(())
+}
+
+fn control(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Piece {
+        Piece::Text(s.to_string())
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(
+            tokenize(r#"git commit -m "a \"b\"" 'c d'"#).unwrap(),
+            [
+                vec![text("git")],
+                vec![text("commit")],
+                vec![text("-m")],
+                vec![text("a \"b\"")],
+                vec![text("c d")],
+            ]
+        );
+        assert_eq!(tokenize("a ''").unwrap(), [vec![text("a")], vec![]]);
+    }
+
+    // the quoting of `easy_process` is the inverse of its parser, so the
+    // words must be split as they are parsed
+    proptest::proptest! {
+        #[test]
+        fn quoted_words(args in proptest::collection::vec("[^{}]*", 1..6)) {
+            let words = tokenize(&easy_process::quote(&args))
+                .unwrap()
+                .into_iter()
+                .map(|pieces| {
+                    pieces
+                        .into_iter()
+                        .map(|piece| match piece {
+                            Piece::Text(text) => text,
+                            Piece::Arg(arg) => panic!("unexpected placeholder: {:?}", arg),
+                        })
+                        .collect::<String>()
+                })
+                .collect::<Vec<_>>();
+            proptest::prop_assert_eq!(words, args);
+        }
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(
+            tokenize("cp {} --to={dst} '{0}x' {{}}").unwrap(),
+            [
+                vec![text("cp")],
+                vec![Piece::Arg(Placeholder::Next)],
+                vec![
+                    text("--to="),
+                    Piece::Arg(Placeholder::Name("dst".to_string()))
+                ],
+                vec![Piece::Arg(Placeholder::Index(0)), text("x")],
+                vec![text("{}")],
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert!(tokenize("").is_err());
+        assert!(tokenize("a | b").is_err());
+        assert!(tokenize("a > b").is_err());
+        assert!(tokenize("a 'b").is_err());
+        assert!(tokenize("a {b").is_err());
+        assert!(tokenize("a b}").is_err());
+        assert!(tokenize("a {b:?}").is_err());
+    }
+}
@@ -0,0 +1,1226 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+#[cfg(feature = "serde")]
+use crate::checked_json;
+use crate::{
+    checked_output, checked_raw_output, dry_run, exec, invocations, redact::Masked, setup_process,
+    trace, Finished, Invocation, LimitPolicy, Output, ParseError, RawOutput, Result, Stage, Vars,
+};
+#[cfg(unix)]
+use crate::{
+    credentials::{self, Credentials},
+    rlimit, Rlimit,
+};
+use os_pipe::PipeReader;
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::{self, ChildStderr, ChildStdin, ChildStdout, Stdio},
+    sync::{atomic::AtomicBool, Arc},
+    time::{Duration, Instant},
+};
+
+/// A process builder, providing fine-grained control over how a new
+/// process should be spawned.
+///
+/// It is constructed from the same literal command line string
+/// accepted by `easy_process::run` or from an already split argv and
+/// keeps the crate's `Output` and [`enum@crate::Error`] semantics, so a non
+/// successful exit status is returned as `Error::Failure`.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// let output = easy_process::Command::new("ls -l")
+///     .cwd("/tmp")
+///     .env("LC_ALL", "C")
+///     .run()?;
+/// println!("{}", output.stdout);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Command {
+    program: Program,
+    cwd: Option<PathBuf>,
+    envs: Vec<(OsString, Option<OsString>)>,
+    vars: Option<Vars>,
+    stdin: Option<Stdio>,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+    opts: exec::Options,
+    dry_run: Option<Output>,
+    #[cfg(unix)]
+    rlimits: Vec<(Rlimit, u64)>,
+    #[cfg(unix)]
+    credentials: Credentials,
+}
+
+enum Program {
+    Line(String),
+    Args(Vec<OsString>),
+}
+
+impl fmt::Debug for Command {
+    /// Shows the command with the registered secrets masked in its
+    /// args and environment.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let envs = self
+            .envs
+            .iter()
+            .map(|(k, v)| {
+                (
+                    Masked(k.to_string_lossy()),
+                    v.as_ref().map(|v| Masked(v.to_string_lossy())),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let mut d = f.debug_struct("Command");
+        d.field("program", &self.program)
+            .field("cwd", &self.cwd)
+            .field("envs", &envs)
+            .field("vars", &self.vars)
+            .field("stdin", &self.stdin)
+            .field("stdout", &self.stdout)
+            .field("stderr", &self.stderr)
+            .field("opts", &self.opts)
+            .field("dry_run", &self.dry_run);
+        #[cfg(unix)]
+        d.field("rlimits", &self.rlimits)
+            .field("credentials", &self.credentials);
+        d.finish()
+    }
+}
+
+impl fmt::Debug for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Program::Line(cmd) => f
+                .debug_tuple("Line")
+                .field(&Masked::from(cmd.as_str()))
+                .finish(),
+            Program::Args(args) => f
+                .debug_tuple("Args")
+                .field(
+                    &args
+                        .iter()
+                        .map(|a| Masked(a.to_string_lossy()))
+                        .collect::<Vec<_>>(),
+                )
+                .finish(),
+        }
+    }
+}
+
+impl Command {
+    /// Constructs a new `Command` from the given command line string.
+    ///
+    /// The string is parsed when the command is run, handling escape
+    /// codes, splitting at whitespace and connecting the stages of
+    /// pipelines such as `dmesg | grep usb`.
+    pub fn new(cmd: &str) -> Command {
+        Command::with_program(Program::Line(cmd.to_string()))
+    }
+
+    /// Constructs a new `Command` from an already split argv, where
+    /// the first element is the program to be run.
+    pub fn from_args<I, S>(args: I) -> Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        Command::with_program(Program::Args(
+            args.into_iter()
+                .map(|a| a.as_ref().to_os_string())
+                .collect(),
+        ))
+    }
+
+    fn with_program(program: Program) -> Command {
+        Command {
+            program,
+            cwd: None,
+            envs: Vec::new(),
+            vars: None,
+            stdin: None,
+            stdout: None,
+            stderr: None,
+            opts: exec::Options::default(),
+            dry_run: None,
+            #[cfg(unix)]
+            rlimits: Vec::new(),
+            #[cfg(unix)]
+            credentials: Credentials::default(),
+        }
+    }
+
+    /// Sets the working directory for the child process.
+    pub fn cwd<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+        self.cwd = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Inserts or updates an environment variable for the child
+    /// process.
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Command
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.envs.push((
+            key.as_ref().to_os_string(),
+            Some(val.as_ref().to_os_string()),
+        ));
+        self
+    }
+
+    /// Removes an environment variable from the child process.
+    pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        self.envs.push((key.as_ref().to_os_string(), None));
+        self
+    }
+
+    /// Expands `$VAR`, `${VAR}` and `${VAR:-default}` in the command
+    /// line string using the environment of the current process.
+    ///
+    /// Variables are not expanded inside single quotes and the
+    /// expanded values are not split in words. The default is parsed
+    /// as a word, so it may be quoted, but variables in it are not
+    /// expanded. A variable which is not set and has no default makes
+    /// the command fail with `ParseError::UnsetVariable`. It has no
+    /// effect on commands built with [`Command::from_args`].
+    pub fn expand_env(&mut self) -> &mut Command {
+        self.vars = Some(Vars::Process);
+        self
+    }
+
+    /// Expands the variables in the command line string as done by
+    /// [`Command::expand_env`], taking their values from the given
+    /// pairs instead of the environment of the current process.
+    pub fn expand_vars<I, K, V>(&mut self, vars: I) -> &mut Command
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.vars = Some(Vars::Map(
+            vars.into_iter()
+                .map(|(k, v)| (k.into(), v.into()))
+                .collect(),
+        ));
+        self
+    }
+
+    /// Configuration for the child process's stdin handle. For
+    /// pipelines it is used by the first stage.
+    ///
+    /// The configuration is consumed by the next spawned process.
+    pub fn stdin<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Command {
+        self.stdin = Some(cfg.into());
+        self
+    }
+
+    /// Configuration for the child process's stdout handle. For
+    /// pipelines it is used by the last stage.
+    ///
+    /// The configuration is consumed by the next spawned process.
+    pub fn stdout<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Command {
+        self.stdout = Some(cfg.into());
+        self
+    }
+
+    /// Configuration for the child process's stderr handle. For
+    /// pipelines it is used by the last stage.
+    ///
+    /// The configuration is consumed by the next spawned process.
+    pub fn stderr<T: Into<Stdio>>(&mut self, cfg: T) -> &mut Command {
+        self.stderr = Some(cfg.into());
+        self
+    }
+
+    /// Sets the maximum time the command is allowed to run when using
+    /// [`Command::run`].
+    ///
+    /// Once the timeout expires the process is asked to terminate
+    /// with `SIGTERM` and, if it is still running after the grace
+    /// period, it is killed with `SIGKILL`. On Windows the process is
+    /// killed right away.
+    ///
+    /// Background processes started by the command which keep its
+    /// stdout or stderr open are not waited for: when the command
+    /// itself exited before the timeout, its exit status is reported
+    /// along with the output read until then.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Command {
+        self.opts.timeout = Some(timeout);
+        self
+    }
+
+    /// Sets how long a timed out process is given to exit after
+    /// `SIGTERM` before being killed. Defaults to 5 seconds.
+    pub fn grace_period(&mut self, grace_period: Duration) -> &mut Command {
+        self.opts.grace_period = grace_period;
+        self
+    }
+
+    /// Enables the dry-run mode for this command, so running it logs
+    /// its argv and returns the given `Output` instead of spawning it.
+    ///
+    /// See [`set_dry_run`](crate::set_dry_run) for enabling it for the
+    /// whole process. [`Command::spawn`] is not affected.
+    pub fn dry_run(&mut self, output: Output) -> &mut Command {
+        self.dry_run = Some(output);
+        self
+    }
+
+    /// Limits the captured stdout to the given number of bytes, see
+    /// [`Command::limit_policy`] for what happens once it is exceeded.
+    /// The lines past the limit are still handed to the line callbacks,
+    /// split in parts of at most the limit. The text output is cut at
+    /// the last character boundary within the limit.
+    pub fn stdout_limit(&mut self, bytes: usize) -> &mut Command {
+        self.opts.stdout_limit = Some(bytes);
+        self
+    }
+
+    /// Limits the captured stderr to the given number of bytes, see
+    /// [`Command::limit_policy`] for what happens once it is exceeded.
+    /// The lines past the limit are still handed to the line callbacks,
+    /// split in parts of at most the limit. The text output is cut at
+    /// the last character boundary within the limit.
+    pub fn stderr_limit(&mut self, bytes: usize) -> &mut Command {
+        self.opts.stderr_limit = Some(bytes);
+        self
+    }
+
+    /// Sets what happens when a stream exceeds its size limit. Defaults
+    /// to [`LimitPolicy::Truncate`].
+    pub fn limit_policy(&mut self, policy: LimitPolicy) -> &mut Command {
+        self.opts.limit_policy = policy;
+        self
+    }
+
+    /// Records a transcript of the output, combining the chunks of
+    /// stdout and stderr in the order they were read and tagging each
+    /// of them with its stream and time since the spawn.
+    ///
+    /// The transcript is available in [`Output::transcript`], on
+    /// success as well as in the output carried by the errors.
+    pub fn transcript(&mut self, enable: bool) -> &mut Command {
+        self.opts.transcript = enable;
+        self
+    }
+
+    /// Runs the command attached to a pseudo-terminal with the given
+    /// window size, so it behaves as when run from an interactive
+    /// terminal.
+    ///
+    /// Everything written to the terminal, including stderr, is
+    /// captured as stdout and the line endings are translated to
+    /// `\r\n` by the terminal. For pipelines the stdout of the last
+    /// stage and the stderr of every stage are attached to it. Stdin is
+    /// not attached, being configured as usual.
+    #[cfg(target_os = "linux")]
+    pub fn pty(&mut self, rows: u16, cols: u16) -> &mut Command {
+        self.opts.pty = Some((rows, cols));
+        self
+    }
+
+    /// Starts the command in its own process group, shared by every
+    /// pipeline stage, so the processes they fork can be signaled
+    /// together.
+    ///
+    /// The whole group is then terminated on timeouts and
+    /// cancellations, and by [`Child::kill`], so no grandchild is left
+    /// running. Pipelines attached to a pseudo-terminal are not
+    /// supported, as their last stage starts a new session.
+    #[cfg(unix)]
+    pub fn process_group(&mut self, enable: bool) -> &mut Command {
+        self.opts.process_group = enable;
+        self
+    }
+
+    /// Limits a resource of every pipeline stage, applied right before
+    /// the program is executed.
+    ///
+    /// Setting a limit above the hard limit of the current process
+    /// makes the spawn to fail, unless running as root. The limits are
+    /// set after switching to the user of [`Command::uid`], so its
+    /// privileges apply. A command killed for exceeding its CPU
+    /// time or file size limit is reported as
+    /// [`ExitKind::ResourceLimit`](crate::ExitKind::ResourceLimit).
+    #[cfg(unix)]
+    pub fn rlimit(&mut self, resource: Rlimit, limit: u64) -> &mut Command {
+        self.rlimits.retain(|(r, _)| *r != resource);
+        self.rlimits.push((resource, limit));
+        self
+    }
+
+    /// Runs every pipeline stage as the given user id.
+    ///
+    /// Unless [`Command::groups`] is also used, the supplementary
+    /// groups are cleared when the current process runs as root.
+    #[cfg(unix)]
+    pub fn uid(&mut self, uid: u32) -> &mut Command {
+        self.credentials.uid = Some(uid);
+        self
+    }
+
+    /// Runs every pipeline stage with the given group id.
+    #[cfg(unix)]
+    pub fn gid(&mut self, gid: u32) -> &mut Command {
+        self.credentials.gid = Some(gid);
+        self
+    }
+
+    /// Sets the supplementary groups of every pipeline stage.
+    #[cfg(unix)]
+    pub fn groups<I: IntoIterator<Item = u32>>(&mut self, groups: I) -> &mut Command {
+        self.credentials.groups = Some(groups.into_iter().collect());
+        self
+    }
+
+    /// Sets `HOME`, `USER` and `LOGNAME` from the passwd entry of the
+    /// user set by [`Command::uid`], or of the current user when not
+    /// set. Variables set with [`Command::env`] take precedence.
+    ///
+    /// The spawn fails if the user has no passwd entry.
+    #[cfg(unix)]
+    pub fn reset_user_env(&mut self, enable: bool) -> &mut Command {
+        self.credentials.reset_env = enable;
+        self
+    }
+
+    /// Emits a `tracing` event, at the debug level, for every line
+    /// written by the command to stdout and stderr.
+    #[cfg(feature = "tracing")]
+    pub fn trace_lines(&mut self, enable: bool) -> &mut Command {
+        self.opts.trace_lines = enable;
+        self
+    }
+
+    /// Kills the command once `cancel` is set, returning
+    /// `Error::Cancelled`.
+    pub(crate) fn cancel_on(&mut self, cancel: Arc<AtomicBool>) -> &mut Command {
+        self.opts.cancel = Some(cancel);
+        self
+    }
+
+    /// Runs the command, waiting for it to finish and collecting its
+    /// output.
+    ///
+    /// By default stdout and stderr are captured and stdin is not
+    /// inherited from the parent.
+    ///
+    /// # Errors
+    ///
+    /// if the exit status is not successful, the timeout expired or a
+    /// `io::Error` was returned.
+    pub fn run(&mut self) -> Result<Output> {
+        checked_output(self.run_with_handlers(exec::Handlers::default())?)
+    }
+
+    /// Runs the command, as done by [`Command::run`], keeping its
+    /// output byte-exact.
+    ///
+    /// # Errors
+    ///
+    /// if the exit status is not successful, in which case
+    /// `Error::RawFailure`, or `Error::RawPipelineFailure` for
+    /// pipelines, is returned, the timeout expired or a `io::Error` was
+    /// returned.
+    pub fn run_bytes(&mut self) -> Result<RawOutput> {
+        checked_raw_output(self.run_with_handlers(exec::Handlers::default())?)
+    }
+
+    /// Runs the command invoking the given callbacks for every line
+    /// written to stdout and stderr as they arrive.
+    ///
+    /// The lines are handed without their terminator and the full
+    /// output is still collected and returned, as done by
+    /// [`Command::run`].
+    ///
+    /// # Errors
+    ///
+    /// if the exit status is not successful, the timeout expired or a
+    /// `io::Error` was returned.
+    pub fn run_streaming<O, E>(&mut self, mut on_stdout: O, mut on_stderr: E) -> Result<Output>
+    where
+        O: FnMut(&str),
+        E: FnMut(&str),
+    {
+        checked_output(self.run_with_handlers(exec::Handlers {
+            stdout: Some(&mut on_stdout),
+            stderr: Some(&mut on_stderr),
+        })?)
+    }
+
+    /// Runs the command, as done by [`Command::run`], parsing its
+    /// stdout as JSON.
+    ///
+    /// # Errors
+    ///
+    /// if the exit status is not successful, stdout is not valid JSON
+    /// for `T`, in which case `Error::Json` is returned, the timeout
+    /// expired or a `io::Error` was returned.
+    #[cfg(feature = "serde")]
+    pub fn run_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        checked_json(self.run_finished()?)
+    }
+
+    /// Runs the command without checking its exit status.
+    pub(crate) fn run_finished(&mut self) -> Result<Finished> {
+        self.run_with_handlers(exec::Handlers::default())
+    }
+
+    fn run_with_handlers(&mut self, handlers: exec::Handlers<'_>) -> Result<Finished> {
+        let stages = self.build(Stdio::null, Stdio::piped)?;
+        if let Some(output) = self.dry_run.clone().or_else(dry_run::global) {
+            return Ok(dry_run::finish(&stages, output));
+        }
+
+        exec::run(stages, &self.opts, handlers)
+    }
+
+    /// Spawns the command as a child process, returning a handle to
+    /// it.
+    ///
+    /// By default stdin, stdout and stderr are inherited from the
+    /// parent; they must be piped in order to be accessible through
+    /// the returned [`Child`].
+    pub fn spawn(&mut self) -> Result<Child> {
+        let stages = self.build(Stdio::inherit, Stdio::inherit)?;
+
+        let invocations = invocations(&stages);
+        let span = trace::Span::new(&invocations);
+        let start = Instant::now();
+        let spawned = exec::spawn(stages, self.opts.process_group)?;
+        span.spawned(spawned.children.iter().map(process::Child::id));
+
+        Ok(Child {
+            stages: spawned.children,
+            merged: spawned.merged,
+            pgid: spawned.pgid,
+            invocations,
+            start,
+            span,
+        })
+    }
+
+    /// Builds the processes of every pipeline stage, using the given
+    /// defaults for the stdio handles which were not configured.
+    fn build(
+        &mut self,
+        default_in: fn() -> Stdio,
+        default_out: fn() -> Stdio,
+    ) -> Result<Vec<Stage>> {
+        let mut stages = match &self.program {
+            Program::Line(cmd) => setup_process(cmd, self.vars.as_ref())?,
+            Program::Args(args) => {
+                let (program, args) = args.split_first().ok_or(ParseError::EmptyCommand)?;
+                let mut p = process::Command::new(program);
+                p.args(args);
+                vec![Stage::from(p)]
+            }
+        };
+
+        #[cfg(unix)]
+        {
+            // the user is switched by the standard library before the
+            // `pre_exec` callbacks run, so the limits are always set
+            // afterwards for consistency
+            credentials::apply(&mut stages, &self.credentials)?;
+            rlimit::apply(&mut stages, &self.rlimits);
+        }
+
+        for Stage { cmd: p, .. } in stages.iter_mut() {
+            if let Some(cwd) = &self.cwd {
+                p.current_dir(cwd);
+            }
+            for (key, val) in &self.envs {
+                match val {
+                    Some(val) => p.env(key, val),
+                    None => p.env_remove(key),
+                };
+            }
+            p.stderr(default_out());
+        }
+
+        let last = stages.len() - 1;
+        stages[0]
+            .cmd
+            .stdin(self.stdin.take().unwrap_or_else(default_in));
+        stages[last]
+            .cmd
+            .stdout(self.stdout.take().unwrap_or_else(default_out));
+        if let Some(stderr) = self.stderr.take() {
+            stages[last].cmd.stderr(stderr);
+        }
+
+        Ok(stages)
+    }
+}
+
+/// Representation of a running child process spawned by
+/// [`Command::spawn`].
+///
+/// For pipelines it holds every stage, with stdin belonging to the
+/// first stage and stdout and stderr to the last one.
+#[derive(Debug)]
+pub struct Child {
+    stages: Vec<process::Child>,
+    merged: Option<PipeReader>,
+    pgid: Option<u32>,
+    invocations: Vec<Invocation>,
+    start: Instant,
+    span: trace::Span,
+}
+
+impl Child {
+    /// Returns the OS-assigned process identifier of the child, or of
+    /// the last stage for pipelines.
+    pub fn id(&self) -> u32 {
+        self.last().id()
+    }
+
+    /// Access to the child's stdin, if it has been captured.
+    pub fn stdin(&mut self) -> &mut Option<ChildStdin> {
+        &mut self.stages[0].stdin
+    }
+
+    /// Access to the child's stdout, if it has been captured.
+    ///
+    /// It is `None` when stdout and stderr are merged with `2>&1`, in
+    /// which case their output is collected by
+    /// [`Child::wait_with_output`].
+    pub fn stdout(&mut self) -> &mut Option<ChildStdout> {
+        &mut self.last_mut().stdout
+    }
+
+    /// Access to the child's stderr, if it has been captured.
+    pub fn stderr(&mut self) -> &mut Option<ChildStderr> {
+        &mut self.last_mut().stderr
+    }
+
+    /// Returns the process group of the child, when started in its
+    /// own with `Command::process_group`.
+    pub fn process_group(&self) -> Option<u32> {
+        self.pgid
+    }
+
+    /// Sends the signal, such as `libc::SIGTERM`, to every process in
+    /// the process group of the child.
+    ///
+    /// # Errors
+    ///
+    /// if the child was not started in its own process group, the
+    /// signal is invalid or it could not be sent.
+    #[cfg(unix)]
+    pub fn signal_group(&self, signal: i32) -> Result<()> {
+        use nix::sys::signal::Signal;
+        use std::convert::TryFrom;
+
+        let pgid = self.pgid.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "child was not started in its own process group",
+            )
+        })?;
+        let signal = Signal::try_from(signal).map_err(io::Error::from)?;
+
+        Ok(exec::signal_group(pgid, signal)?)
+    }
+
+    /// Forces the child process, and every pipeline stage, to exit.
+    /// When started in its own process group, the whole group is
+    /// killed.
+    pub fn kill(&mut self) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(pgid) = self.pgid {
+            exec::signal_group(pgid, nix::sys::signal::Signal::SIGKILL)?;
+        }
+        for stage in self.stages.iter_mut() {
+            stage.kill()?;
+        }
+        Ok(())
+    }
+
+    /// Waits for the child, and every pipeline stage, to exit
+    /// completely.
+    ///
+    /// # Errors
+    ///
+    /// if the exit status of any stage is not successful or a
+    /// `io::Error` was returned.
+    pub fn wait(&mut self) -> Result<()> {
+        // stdin is closed so the child does not wait for input forever
+        drop(self.stdin().take());
+
+        let statuses = self
+            .stages
+            .iter_mut()
+            .map(process::Child::wait)
+            .collect::<io::Result<Vec<_>>>()?;
+        let finished = Finished {
+            statuses,
+            output: RawOutput::default(),
+            invocations: self.invocations.clone(),
+            duration: self.start.elapsed(),
+        };
+        self.span.finish(Ok(&finished));
+
+        checked_output(finished).map(|_| ())
+    }
+
+    /// Waits for the child, and every pipeline stage, to exit and
+    /// collects all remaining output on its stdout and stderr handles.
+    ///
+    /// # Errors
+    ///
+    /// if the exit status of any stage is not successful or a
+    /// `io::Error` was returned.
+    pub fn wait_with_output(mut self) -> Result<Output> {
+        drop(self.stdin().take());
+
+        // the last stage is collected first as it drives the pipeline
+        let merged = match self.merged.take() {
+            Some(mut reader) => {
+                let mut data = Vec::new();
+                reader.read_to_end(&mut data)?;
+                Some(data)
+            }
+            None => None,
+        };
+        let mut o = self.stages.pop().unwrap().wait_with_output()?;
+        if let Some(merged) = merged {
+            o.stdout = merged;
+        }
+        let mut statuses = self
+            .stages
+            .iter_mut()
+            .map(process::Child::wait)
+            .collect::<io::Result<Vec<_>>>()?;
+        statuses.push(o.status);
+        let finished = Finished {
+            statuses,
+            output: RawOutput {
+                stdout: o.stdout,
+                stderr: o.stderr,
+                ..RawOutput::default()
+            },
+            invocations: self.invocations,
+            duration: self.start.elapsed(),
+        };
+        self.span.finish(Ok(&finished));
+
+        checked_output(finished)
+    }
+
+    fn last(&self) -> &process::Child {
+        self.stages.last().unwrap()
+    }
+
+    fn last_mut(&mut self) -> &mut process::Child {
+        self.stages.last_mut().unwrap()
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::{Error, ExitKind, Stream};
+
+    #[test]
+    fn cwd_and_env() {
+        let output = Command::new(r#"sh -c 'pwd; echo "$FOO"'"#)
+            .cwd("/")
+            .env("FOO", "bar")
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "/\nbar\n");
+    }
+
+    #[test]
+    fn expand_vars() {
+        let output = Command::new(r#"echo "$GREETING, ${NAME:-world}" '$NAME'"#)
+            .expand_vars([("GREETING", "Hello")])
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "Hello, world $NAME\n");
+
+        let err = Command::new("echo $EASY_PROCESS_UNSET")
+            .expand_env()
+            .run()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "unable to parse command: unset variable: EASY_PROCESS_UNSET"
+        );
+    }
+
+    #[test]
+    fn dry_run() {
+        let output = Command::new("easy-process-missing --flag | wc -l")
+            .dry_run(Output {
+                stdout: "synthetic".to_string(),
+                ..Output::default()
+            })
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "synthetic");
+
+        assert!(matches!(
+            Command::new("echo 'a").dry_run(Output::default()).run(),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn output_limit() {
+        let output = Command::new("sh -c 'printf 0123456789; printf abc >&2'")
+            .stdout_limit(4)
+            .stderr_limit(3)
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "0123");
+        assert!(output.stdout_truncated);
+        assert_eq!(&output.stderr, "abc");
+        assert!(!output.stderr_truncated);
+
+        // the text is cut at a character boundary
+        let output = Command::new("printf 'aé'").stdout_limit(2).run().unwrap();
+        assert_eq!(&output.stdout, "a");
+        let output = Command::new("printf 'aé'")
+            .stdout_limit(2)
+            .run_bytes()
+            .unwrap();
+        assert_eq!(output.stdout, b"a\xc3");
+
+        // the lines past the limit are still handed to the callbacks
+        let mut lines = Vec::new();
+        let output = Command::new("printf '1\\n2\\n3\\n4\\n5\\n'")
+            .stdout_limit(6)
+            .run_streaming(|l| lines.push(l.to_string()), |_| {})
+            .unwrap();
+        assert_eq!(&output.stdout, "1\n2\n3\n");
+        assert!(output.stdout_truncated);
+        assert_eq!(lines, ["1", "2", "3", "4", "5"]);
+
+        // the lines longer than the limit are split
+        let mut lines = Vec::new();
+        Command::new("printf '0123456789\\n12\\n'")
+            .stdout_limit(4)
+            .run_streaming(|l| lines.push(l.to_string()), |_| {})
+            .unwrap();
+        assert_eq!(lines, ["0123", "4567", "89", "12"]);
+
+        match Command::new("yes")
+            .stdout_limit(100)
+            .limit_policy(LimitPolicy::Kill)
+            .run()
+        {
+            Err(Error::OutputLimitExceeded(output, limit, invocation)) => {
+                assert_eq!(limit, 100);
+                assert_eq!(output.stdout, "y\n".repeat(50));
+                assert!(output.stdout_truncated);
+                assert_eq!(invocation.program, "yes");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn transcript() {
+        let cmd = "sh -c 'echo out; sleep 0.1; echo err >&2; sleep 0.1; echo out; exit 1'";
+        let output = match Command::new(cmd).transcript(true).run() {
+            Err(Error::Failure(_, output, _)) => output,
+            r => panic!("unexpected result: {:?}", r),
+        };
+        let chunks = output
+            .transcript
+            .iter()
+            .map(|c| (c.stream, c.data.as_slice()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            chunks,
+            [
+                (Stream::Stdout, &b"out\n"[..]),
+                (Stream::Stderr, b"err\n"),
+                (Stream::Stdout, b"out\n")
+            ]
+        );
+        assert!(output
+            .transcript
+            .windows(2)
+            .all(|c| c[0].elapsed <= c[1].elapsed));
+
+        let output = Command::new("echo out").run().unwrap();
+        assert!(output.transcript.is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn pty() {
+        let output = Command::new("sh -c 'test -t 1 && test -t 2 && stty size < /dev/tty'")
+            .pty(24, 80)
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "24 80\r\n");
+
+        let output = Command::new("sh -c 'stty size < /dev/tty; ps -o pgid= -o sid= -p $$'")
+            .pty(24, 80)
+            .process_group(true)
+            .run()
+            .unwrap();
+        let mut lines = output.stdout.lines();
+        assert_eq!(lines.next(), Some("24 80"));
+        let ids = lines.next().unwrap().split_whitespace().collect::<Vec<_>>();
+        assert_eq!(ids.len(), 2);
+        assert_eq!(ids[0], ids[1]);
+        assert!(matches!(
+            Command::new("echo a | cat").pty(24, 80).process_group(true).run(),
+            Err(Error::Io(e)) if e.kind() == io::ErrorKind::InvalidInput
+        ));
+
+        match Command::new("sh -c 'echo err >&2; exit 3'")
+            .pty(24, 80)
+            .run()
+        {
+            Err(Error::Failure(ex, output, _)) => {
+                assert_eq!(ex.code(), Some(3));
+                assert_eq!(&output.stdout, "err\r\n");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn process_group() {
+        // the forked subshell must be killed along with the shell
+        let dir = std::env::temp_dir().join(format!("easy-process-pgid-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        match Command::new("sh -c '(sleep 0.5; touch marker) & sleep 10'")
+            .cwd(&dir)
+            .process_group(true)
+            .timeout(Duration::from_millis(200))
+            .run()
+        {
+            Err(Error::Timeout(..)) => {}
+            r => panic!("unexpected result: {:?}", r),
+        }
+        std::thread::sleep(Duration::from_secs(1));
+        assert!(!dir.join("marker").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut child = Command::new("sh -c 'sleep 10 | sleep 10'")
+            .process_group(true)
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        assert_eq!(child.process_group(), Some(child.id()));
+        child.signal_group(15).unwrap();
+        assert!(matches!(child.wait(), Err(Error::Failure(..))));
+
+        let mut child = Command::new("sleep 10").spawn().unwrap();
+        assert!(child.signal_group(15).is_err());
+        child.kill().unwrap();
+        let _ = child.wait();
+    }
+
+    #[test]
+    fn rlimit() {
+        let e = Command::new("sh -c 'while :; do :; done'")
+            .rlimit(Rlimit::Cpu, 1)
+            .run()
+            .unwrap_err();
+        assert!(matches!(
+            e.exit(),
+            Some(ExitKind::ResourceLimit {
+                resource: Rlimit::Cpu,
+                ..
+            })
+        ));
+        assert!(e.to_string().contains("exceeded the CPU time limit"));
+
+        let dir = std::env::temp_dir().join(format!("easy-process-rlimit-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let e = Command::new("head -c 4096 /dev/zero > out")
+            .cwd(&dir)
+            .rlimit(Rlimit::FileSize, 1024)
+            .run()
+            .unwrap_err();
+        assert!(matches!(
+            e.exit(),
+            Some(ExitKind::ResourceLimit {
+                resource: Rlimit::FileSize,
+                ..
+            })
+        ));
+        assert_eq!(std::fs::metadata(dir.join("out")).unwrap().len(), 1024);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let output = Command::new("sh -c 'ulimit -n; ulimit -c'")
+            .rlimit(Rlimit::OpenFiles, 64)
+            .rlimit(Rlimit::Core, 0)
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "64\n0\n");
+    }
+
+    #[test]
+    fn credentials() {
+        use nix::unistd::{Uid, User};
+
+        let cmd = r#"sh -c 'id -u; id -g; id -G; echo "$HOME $USER"'"#;
+        let output = Command::new(cmd).reset_user_env(true).run().unwrap();
+        let current = User::from_uid(Uid::current()).unwrap().unwrap();
+        assert!(output
+            .stdout
+            .ends_with(&format!("{} {}\n", current.dir.display(), current.name)));
+
+        // dropping privileges requires running as root
+        let nobody = match User::from_name("nobody").unwrap() {
+            Some(nobody) if Uid::effective().is_root() => nobody,
+            _ => return,
+        };
+        let (uid, gid) = (nobody.uid.as_raw(), nobody.gid.as_raw());
+        let output = Command::new(cmd)
+            .uid(uid)
+            .gid(gid)
+            .reset_user_env(true)
+            .env("USER", "custom")
+            .run()
+            .unwrap();
+        assert_eq!(
+            output.stdout,
+            format!(
+                "{}\n{}\n{}\n{} custom\n",
+                uid,
+                gid,
+                gid,
+                nobody.dir.display()
+            )
+        );
+
+        let output = Command::new("id -G")
+            .uid(uid)
+            .gid(gid)
+            .groups([gid, 0])
+            .run()
+            .unwrap();
+        assert_eq!(output.stdout, format!("{} 0\n", gid));
+    }
+
+    #[cfg(feature = "macros")]
+    #[test]
+    fn cmd_macro() {
+        use crate::cmd;
+
+        let argv = |cmd: Command| match cmd.program {
+            Program::Args(args) => args,
+            Program::Line(_) => unreachable!(),
+        };
+
+        // the words are split as done by the parser
+        macro_rules! assert_parsed {
+            ($($line:literal),*) => {$(
+                let parsed = crate::parser::parse($line, None).unwrap().remove(0).args;
+                let parsed = parsed.into_iter().map(OsString::from).collect::<Vec<_>>();
+                assert_eq!(argv(cmd!($line)), parsed, "{}", $line);
+            )*};
+        }
+        assert_parsed!(
+            r#"a "b c" 'd\'e' f\ g"#,
+            r#"x "\t\"\$" '' "" a\nb"#,
+            "echo $HOME"
+        );
+
+        let msg = "it's \"quoted\" | not > parsed";
+        let dir = Path::new("/my dir");
+        assert_eq!(
+            argv(cmd!(
+                "git -C {} commit -m {msg} --author={0}{{}} '{n}'",
+                dir,
+                n = ""
+            )),
+            [
+                "git",
+                "-C",
+                "/my dir",
+                "commit",
+                "-m",
+                msg,
+                "--author=/my dir{}",
+                ""
+            ]
+        );
+
+        // the captures do not clash with the temporaries of the macro
+        let word = "x";
+        let __arg0 = "y";
+        assert_eq!(
+            argv(cmd!("echo a{word} {} {__arg0}", "z")),
+            ["echo", "ax", "z", "y"]
+        );
+
+        let output = cmd!(r#"printf "%s\n" {msg}"#).run().unwrap();
+        assert_eq!(output.stdout, format!("{}\n", msg));
+    }
+
+    #[test]
+    fn env_remove() {
+        let output = Command::from_args(["sh", "-c", "echo \"${HOME:-unset}\""])
+            .env_remove("HOME")
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "unset\n");
+    }
+
+    #[test]
+    fn failing_spawn() {
+        let child = Command::from_args(["sh", "-c", "echo error >&2; exit 2"])
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        match child.wait_with_output() {
+            Err(Error::Failure(ex, output, _)) => {
+                assert_eq!(ex.code(), Some(2));
+                assert_eq!(&output.stderr, "error\n");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn pipeline() {
+        let output = Command::new(r#"printf 'a\nb\nc\n' | grep -v b | tr a-z A-Z"#)
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "A\nC\n");
+    }
+
+    #[test]
+    fn pipeline_failure() {
+        match Command::new(r#"sh -c 'echo error >&2; exit 3' | cat"#).run() {
+            Err(Error::PipelineFailure(stage, ex, output, invocation)) => {
+                assert_eq!(stage, 0);
+                assert_eq!(&invocation.program, "sh");
+                assert_eq!(ex.code(), Some(3));
+                assert_eq!(&output.stderr, "error\n");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn spawn_pipeline() {
+        let mut child = Command::new("rev | tr a-z A-Z")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        io::Write::write_all(child.stdin().as_mut().unwrap(), b"abc").unwrap();
+        assert!(child.wait_with_output().unwrap().stdout.starts_with("CBA"));
+    }
+
+    #[test]
+    fn redirections() {
+        let dir = std::env::temp_dir().join(format!("easy-process-{}", process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        Command::new("sh -c 'echo out; echo err >&2' > out 2> err")
+            .cwd(&dir)
+            .run()
+            .unwrap();
+        Command::new("echo more >> out").cwd(&dir).run().unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dir.join("out")).unwrap(),
+            "out\nmore\n"
+        );
+        assert_eq!(std::fs::read_to_string(dir.join("err")).unwrap(), "err\n");
+
+        let output = Command::new("tr a-z A-Z < out").cwd(&dir).run().unwrap();
+        assert_eq!(&output.stdout, "OUT\nMORE\n");
+
+        let output = Command::new("sh -c 'echo out; echo err >&2' 2>&1 | sort")
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "err\nout\n");
+        assert_eq!(&output.stderr, "");
+
+        let output = Command::new("sh -c 'echo err >&2' 2>&1").run().unwrap();
+        assert_eq!(&output.stdout, "err\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn streaming() {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let output = Command::new(r#"sh -c 'echo 1; echo 2 >&2; printf 3'"#)
+            .run_streaming(|l| out.push(l.to_string()), |l| err.push(l.to_string()))
+            .unwrap();
+        assert_eq!(out, ["1", "3"]);
+        assert_eq!(err, ["2"]);
+        assert_eq!(&output.stdout, "1\n3");
+        assert_eq!(&output.stderr, "2\n");
+
+        // the lines of each stage are split apart
+        let mut err = Vec::new();
+        Command::new("sh -c 'printf a >&2; sleep 0.2; echo b >&2' | sh -c 'sleep 0.1; echo X >&2'")
+            .run_streaming(|_| {}, |l| err.push(l.to_string()))
+            .unwrap();
+        assert_eq!(err, ["X", "ab"]);
+    }
+
+    #[test]
+    fn timeout() {
+        match Command::new(r#"sh -c 'echo partial; exec sleep 10'"#)
+            .timeout(Duration::from_millis(500))
+            .run()
+        {
+            Err(Error::Timeout(output, elapsed, invocation)) => {
+                assert_eq!(invocation.duration, elapsed);
+                assert_eq!(&output.stdout, "partial\n");
+                assert!(elapsed >= Duration::from_millis(500));
+                assert!(elapsed < Duration::from_secs(5));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn timeout_kills_after_grace_period() {
+        match Command::new(r#"sh -c 'trap "" TERM; while true; do sleep 0.1; done'"#)
+            .timeout(Duration::from_millis(200))
+            .grace_period(Duration::from_millis(200))
+            .run()
+        {
+            Err(Error::Timeout(_, elapsed, _)) => assert!(elapsed >= Duration::from_millis(400)),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn background_process_after_timeout() {
+        let start = Instant::now();
+        let output = Command::new("sh -c 'sleep 3 & echo hi'")
+            .timeout(Duration::from_millis(300))
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "hi\n");
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[test]
+    fn finishes_before_timeout() {
+        let output = Command::new("echo ok")
+            .timeout(Duration::from_secs(5))
+            .run()
+            .unwrap();
+        assert_eq!(&output.stdout, "ok\n");
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::Stage;
+use nix::{
+    libc,
+    unistd::{Uid, User},
+};
+use std::{io, os::unix::process::CommandExt, process};
+
+/// User and groups the stages are run as.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Credentials {
+    pub(crate) uid: Option<u32>,
+    pub(crate) gid: Option<u32>,
+    pub(crate) groups: Option<Vec<u32>>,
+    pub(crate) reset_env: bool,
+}
+
+impl Credentials {
+    fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.groups.is_none() && !self.reset_env
+    }
+}
+
+/// Switches the user and groups of every stage right before the exec,
+/// setting `HOME`, `USER` and `LOGNAME` from the user's passwd entry
+/// when asked to.
+///
+/// The user and group are set by the standard library, which clears
+/// the supplementary groups when root drops its privileges. Explicit
+/// groups must be set before the user is switched, so in that case
+/// they are all set by a `pre_exec` callback instead.
+pub(crate) fn apply(stages: &mut [Stage], creds: &Credentials) -> io::Result<()> {
+    if creds.is_empty() {
+        return Ok(());
+    }
+
+    if creds.reset_env {
+        let uid = creds.uid.map_or_else(Uid::current, Uid::from_raw);
+        let user = User::from_uid(uid)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no passwd entry for uid {}", uid),
+            )
+        })?;
+        for stage in stages.iter_mut() {
+            stage
+                .cmd
+                .env("HOME", &user.dir)
+                .env("USER", &user.name)
+                .env("LOGNAME", &user.name);
+        }
+    }
+
+    for stage in stages.iter_mut() {
+        match &creds.groups {
+            Some(groups) => switch_user(&mut stage.cmd, groups.clone(), creds.gid, creds.uid),
+            None => {
+                if let Some(gid) = creds.gid {
+                    stage.cmd.gid(gid);
+                }
+                if let Some(uid) = creds.uid {
+                    stage.cmd.uid(uid);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// the number of groups is a `size_t` on Linux but a `c_int` on macOS
+#[allow(unsafe_code, trivial_numeric_casts)]
+fn switch_user(cmd: &mut process::Command, groups: Vec<u32>, gid: Option<u32>, uid: Option<u32>) {
+    // SAFETY: only async-signal-safe functions are called between the
+    // fork and the exec. The groups are set first, as doing it requires
+    // the privileges dropped by setuid.
+    unsafe {
+        cmd.pre_exec(move || {
+            if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            if let Some(gid) = gid {
+                if libc::setgid(gid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            if let Some(uid) = uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{command_line, invocations, status::status_from_raw, Finished, Output, Stage};
+use std::{sync::Mutex, time::Duration};
+
+static GLOBAL: Mutex<Option<Output>> = Mutex::new(None);
+
+/// Enables or disables the dry-run mode for the whole process.
+///
+/// While enabled, the commands are parsed and their argv logged at the
+/// `info` level, but none is spawned and the given `Output` is
+/// returned as if they had succeeded. It applies to
+/// `easy_process::run`, `easy_process::run_with_stdin`, their
+/// asynchronous versions and the [`Command`](crate::Command) run methods;
+/// [`Command::dry_run`](crate::Command::dry_run) enables it for a single
+/// command.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// easy_process::set_dry_run(Some(easy_process::Output::default()));
+/// easy_process::run("rm -rf /srv/old-release")?;
+/// easy_process::set_dry_run(None);
+/// # Ok(())
+/// # }
+/// ```
+pub fn set_dry_run(output: Option<Output>) {
+    *GLOBAL.lock().unwrap_or_else(|e| e.into_inner()) = output;
+}
+
+/// Returns the output of the process wide dry-run mode, if enabled.
+pub(crate) fn global() -> Option<Output> {
+    GLOBAL.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Logs the argv of the stages, returning them as successfully
+/// finished with the given output.
+pub(crate) fn finish(stages: &[Stage], output: Output) -> Finished {
+    let invocations = invocations(stages);
+    log::info!("dry run: {}", command_line(&invocations));
+
+    Finished {
+        statuses: invocations
+            .iter()
+            .map(|_| status_from_raw(0).expect("zero is a valid exit status"))
+            .collect(),
+        output: output.into(),
+        invocations,
+        duration: Duration::default(),
+    }
+}
@@ -0,0 +1,719 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{invocations, trace, Chunk, Error, Finished, Invocation, RawOutput, Result, Stage};
+use os_pipe::PipeReader;
+use std::{
+    borrow::Cow,
+    io::{self, Read},
+    process::{self, ExitStatus, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Interval used to poll a child which already closed its output
+/// pipes but has not yet exited, and to check for cancellation.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Default time a process is given to exit after `SIGTERM` before
+/// being killed.
+pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(5);
+
+/// One of the output streams of a process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stream {
+    /// The standard output
+    Stdout,
+    /// The standard error
+    Stderr,
+}
+
+impl Stream {
+    fn name(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Data read from one of the forwarded pipes, which is identified by
+/// its index so the lines of each pipe are split apart.
+#[derive(Debug)]
+enum Event {
+    Data(Stream, usize, Vec<u8>),
+    Done(Stream, usize, io::Result<()>),
+}
+
+/// Callback invoked for every line written by the process.
+pub(crate) type LineCallback<'a> = &'a mut dyn FnMut(&str);
+
+/// Per line callbacks for the process' stdout and stderr.
+#[derive(Default)]
+pub(crate) struct Handlers<'a> {
+    pub(crate) stdout: Option<LineCallback<'a>>,
+    pub(crate) stderr: Option<LineCallback<'a>>,
+}
+
+/// Accumulates the data of one stream, splitting it in lines when a
+/// callback is registered or the lines are traced.
+struct Capture<'a> {
+    stream: Stream,
+    data: Vec<u8>,
+    /// The line being read from each pipe, which is kept apart from
+    /// `data` so the lines past the size limit are still split.
+    lines: Vec<Vec<u8>>,
+    on_line: Option<LineCallback<'a>>,
+    trace_lines: bool,
+    limit: Option<usize>,
+    truncated: bool,
+}
+
+impl<'a> Capture<'a> {
+    fn new(stream: Stream, on_line: Option<LineCallback<'a>>, opts: &Options) -> Self {
+        Capture {
+            stream,
+            data: Vec::new(),
+            lines: Vec::new(),
+            on_line,
+            trace_lines: opts.trace_lines,
+            limit: match stream {
+                Stream::Stdout => opts.stdout_limit,
+                Stream::Stderr => opts.stderr_limit,
+            },
+            truncated: false,
+        }
+    }
+
+    /// Appends the data read from the `source` pipe, returning how much
+    /// of it was kept.
+    fn push(&mut self, source: usize, data: &[u8]) -> usize {
+        if self.on_line.is_some() || self.trace_lines {
+            if self.lines.len() <= source {
+                self.lines.resize_with(source + 1, Vec::new);
+            }
+            let mut rest = data;
+            while let Some(pos) = rest.iter().position(|&b| b == b'\n') {
+                self.extend_line(source, &rest[.. pos]);
+                self.emit(source);
+                rest = &rest[pos + 1 ..];
+            }
+            self.extend_line(source, rest);
+        }
+
+        // the data past the limit is discarded, still being read so
+        // the process does not block on a full pipe
+        let mut kept = data;
+        if let Some(limit) = self.limit {
+            let room = limit.saturating_sub(self.data.len());
+            if kept.len() > room {
+                kept = &kept[.. room];
+                self.truncated = true;
+            }
+        }
+        self.data.extend_from_slice(kept);
+
+        kept.len()
+    }
+
+    /// Appends to the line being read. A line longer than the size
+    /// limit is handed out in parts of at most the limit, so it is never
+    /// fully buffered.
+    fn extend_line(&mut self, source: usize, mut data: &[u8]) {
+        if let Some(limit) = self.limit.map(|l| l.max(1)) {
+            while self.lines[source].len() + data.len() > limit {
+                let (head, tail) = data.split_at(limit - self.lines[source].len());
+                self.lines[source].extend_from_slice(head);
+                self.emit(source);
+                data = tail;
+            }
+        }
+        self.lines[source].extend_from_slice(data);
+    }
+
+    /// Flushes the last line of the `source` pipe when it is not
+    /// newline terminated.
+    fn finish(&mut self, source: usize) {
+        if self.lines.get(source).is_some_and(|l| !l.is_empty()) {
+            self.emit(source);
+        }
+    }
+
+    fn emit(&mut self, source: usize) {
+        let line = line(&self.lines[source]);
+        if self.trace_lines {
+            trace::line(self.stream.name(), &line);
+        }
+        if let Some(on_line) = self.on_line.as_mut() {
+            on_line(&line);
+        }
+        self.lines[source].clear();
+    }
+}
+
+/// Collects the output of both streams as it arrives.
+struct Collector<'a> {
+    stdout: Capture<'a>,
+    stderr: Capture<'a>,
+    /// The chunks of both streams, in the order they arrived, when a
+    /// transcript is requested.
+    transcript: Option<Vec<Chunk>>,
+    start: Instant,
+}
+
+impl<'a> Collector<'a> {
+    fn new(handlers: Handlers<'a>, opts: &Options, start: Instant) -> Self {
+        Collector {
+            stdout: Capture::new(Stream::Stdout, handlers.stdout, opts),
+            stderr: Capture::new(Stream::Stderr, handlers.stderr, opts),
+            transcript: if opts.transcript {
+                Some(Vec::new())
+            } else {
+                None
+            },
+            start,
+        }
+    }
+
+    fn capture(&mut self, stream: Stream) -> &mut Capture<'a> {
+        match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        }
+    }
+
+    fn push(&mut self, stream: Stream, source: usize, data: &[u8]) {
+        let kept = self.capture(stream).push(source, data);
+        if let Some(transcript) = self.transcript.as_mut() {
+            if kept > 0 {
+                transcript.push(Chunk {
+                    stream,
+                    elapsed: self.start.elapsed(),
+                    data: data[.. kept].to_vec(),
+                });
+            }
+        }
+    }
+
+    /// Flushes the last line of every pipe which is not newline
+    /// terminated.
+    fn finish(&mut self) {
+        for capture in [&mut self.stdout, &mut self.stderr] {
+            for source in 0 .. capture.lines.len() {
+                capture.finish(source);
+            }
+        }
+    }
+
+    fn output(self) -> RawOutput {
+        RawOutput {
+            stdout: self.stdout.data,
+            stderr: self.stderr.data,
+            stdout_truncated: self.stdout.truncated,
+            stderr_truncated: self.stderr.truncated,
+            transcript: self.transcript.unwrap_or_default(),
+        }
+    }
+}
+
+fn line(data: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(data.strip_suffix(b"\r").unwrap_or(data))
+}
+
+/// What happens when an output stream exceeds its size limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LimitPolicy {
+    /// The output is truncated at the limit, marking it as truncated
+    /// in the returned output, and the process keeps running.
+    #[default]
+    Truncate,
+    /// The process is killed and `Error::OutputLimitExceeded` is
+    /// returned.
+    Kill,
+}
+
+#[derive(Debug)]
+pub(crate) struct Options {
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) grace_period: Duration,
+    /// Kills the process once set.
+    pub(crate) cancel: Option<Arc<AtomicBool>>,
+    /// Emits a `tracing` event for every line of output.
+    pub(crate) trace_lines: bool,
+    pub(crate) stdout_limit: Option<usize>,
+    pub(crate) stderr_limit: Option<usize>,
+    pub(crate) limit_policy: LimitPolicy,
+    /// Records the combined transcript of the output.
+    pub(crate) transcript: bool,
+    /// Window size, in rows and columns, of the pseudo-terminal the
+    /// process is attached to.
+    #[cfg(target_os = "linux")]
+    pub(crate) pty: Option<(u16, u16)>,
+    /// Starts the process in its own process group, so the whole tree
+    /// can be signaled.
+    pub(crate) process_group: bool,
+}
+
+impl Options {
+    fn cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|c| c.load(Ordering::SeqCst))
+    }
+
+    /// Returns when the process must be checked next, being the
+    /// deadline or, when it can be cancelled, the next poll.
+    fn next_check(&self, deadline: Option<Instant>) -> Option<Instant> {
+        let poll = self.cancel.as_ref().map(|_| Instant::now() + POLL_INTERVAL);
+        match (deadline, poll) {
+            (Some(deadline), Some(poll)) => Some(deadline.min(poll)),
+            (deadline, poll) => deadline.or(poll),
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            timeout: None,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            cancel: None,
+            trace_lines: false,
+            stdout_limit: None,
+            stderr_limit: None,
+            limit_policy: LimitPolicy::default(),
+            transcript: false,
+            #[cfg(target_os = "linux")]
+            pty: None,
+            process_group: false,
+        }
+    }
+}
+
+/// The spawned stages of a pipeline.
+#[derive(Debug)]
+pub(crate) struct Spawned {
+    pub(crate) children: Vec<process::Child>,
+    /// The pipe shared by stdout and stderr of the last stage, when
+    /// they are merged with `2>&1`.
+    pub(crate) merged: Option<PipeReader>,
+    /// The process group of every stage, when started in their own.
+    pub(crate) pgid: Option<u32>,
+}
+
+/// Spawns every pipeline stage, connecting the stdout of each stage
+/// to the stdin of the next one.
+///
+/// With `process_group`, every stage is put in a new process group led
+/// by the first stage. A stage starting a new session already leads a
+/// group of its own, so it is only supported for a single stage. It is
+/// ignored on Windows.
+#[cfg_attr(windows, allow(unused_variables))]
+pub(crate) fn spawn(stages: Vec<Stage>, process_group: bool) -> io::Result<Spawned> {
+    let last = stages.len() - 1;
+    #[cfg(unix)]
+    if process_group && last > 0 && stages.iter().any(|s| s.session) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a pipeline attached to a pseudo-terminal cannot share a process group",
+        ));
+    }
+    let mut children: Vec<process::Child> = Vec::with_capacity(stages.len());
+    let mut merged: Option<PipeReader> = None;
+    for (i, mut stage) in stages.into_iter().enumerate() {
+        if i < last {
+            stage.cmd.stdout(Stdio::piped());
+        }
+        if let Some(prev) = children.last_mut() {
+            // a previous stage with stdout redirected to a file gives
+            // no input
+            let input = match (merged.take(), prev.stdout.take()) {
+                (Some(reader), _) => Stdio::from(reader),
+                (None, Some(stdout)) => Stdio::from(stdout),
+                (None, None) => Stdio::null(),
+            };
+            stage.cmd.stdin(input);
+        }
+        #[cfg(unix)]
+        // `setsid` fails for a group leader, so a stage starting a new
+        // session is left to create its group
+        if process_group && !stage.session {
+            use std::os::unix::process::CommandExt;
+
+            // zero makes the first stage the leader of a new group
+            let pgid = children.first().map_or(0, |c| c.id() as i32);
+            stage.cmd.process_group(pgid);
+        }
+        let spawned = stage.redirect().and_then(|m| {
+            merged = m;
+            stage.cmd.spawn()
+        });
+        match spawned {
+            Ok(child) => children.push(child),
+            Err(e) => {
+                for mut child in children {
+                    let _ = child.kill();
+                    let _ = child.wait();
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    let pgid = match cfg!(unix) && process_group {
+        true => Some(children[0].id()),
+        false => None,
+    };
+
+    Ok(Spawned {
+        children,
+        merged,
+        pgid,
+    })
+}
+
+/// Spawns the command and collects its output, enforcing the given
+/// options while the process runs.
+///
+/// The exit status of every stage is returned so the caller can
+/// check them.
+pub(crate) fn run(stages: Vec<Stage>, opts: &Options, handlers: Handlers<'_>) -> Result<Finished> {
+    let invocations = invocations(&stages);
+    let span = trace::Span::new(&invocations);
+    let res = span.in_scope(|| run_traced(stages, invocations, opts, handlers, &span));
+    span.finish(res.as_ref());
+
+    res
+}
+
+fn run_traced(
+    #[allow(unused_mut)] mut stages: Vec<Stage>,
+    mut invocations: Vec<Invocation>,
+    opts: &Options,
+    handlers: Handlers<'_>,
+    span: &trace::Span,
+) -> Result<Finished> {
+    let start = Instant::now();
+    let deadline = opts.timeout.map(|t| start + t);
+    #[cfg(target_os = "linux")]
+    let terminal = match opts.pty {
+        Some((rows, cols)) => Some(crate::pty::attach(&mut stages, rows, cols)?),
+        None => None,
+    };
+    let Spawned {
+        mut children,
+        merged,
+        pgid,
+    } = spawn(stages, opts.process_group)?;
+    span.spawned(children.iter().map(process::Child::id));
+    // stdin is closed so the child does not wait for input forever
+    drop(children[0].stdin.take());
+
+    let (tx, rx) = mpsc::channel();
+    // the pipes of each stream are numbered in the order they are
+    // forwarded
+    let (mut open_stdout, mut open_stderr) = (0, 0);
+    if let Some(stdout) = merged {
+        forward(stdout, Stream::Stdout, open_stdout, tx.clone());
+        open_stdout += 1;
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(terminal) = terminal {
+        forward(terminal, Stream::Stdout, open_stdout, tx.clone());
+        open_stdout += 1;
+    }
+    if let Some(stdout) = children.last_mut().unwrap().stdout.take() {
+        forward(stdout, Stream::Stdout, open_stdout, tx.clone());
+        open_stdout += 1;
+    }
+    for child in children.iter_mut() {
+        if let Some(stderr) = child.stderr.take() {
+            forward(stderr, Stream::Stderr, open_stderr, tx.clone());
+            open_stderr += 1;
+        }
+    }
+    drop(tx);
+
+    let mut collector = Collector::new(handlers, opts, start);
+    let expired = |deadline: Option<Instant>| deadline.is_some_and(|d| Instant::now() >= d);
+    // set when the children exited before the pipes were closed
+    let mut exited = None;
+    while open_stdout + open_stderr > 0 {
+        match next_event(&rx, opts.next_check(deadline)) {
+            Ok(Event::Data(stream, source, data)) => collector.push(stream, source, &data),
+            Ok(Event::Done(stream, source, res)) => {
+                res?;
+                match stream {
+                    Stream::Stdout => open_stdout -= 1,
+                    Stream::Stderr => open_stderr -= 1,
+                }
+                collector.capture(stream).finish(source);
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+            Err(RecvTimeoutError::Timeout) if opts.cancelled() => {
+                kill_all(&mut children, pgid)?;
+                return Err(Error::Cancelled);
+            }
+            Err(RecvTimeoutError::Timeout) if expired(deadline) => {
+                // background processes left by the children may keep
+                // the pipes open, which are not waited for
+                exited = wait_until(&mut children, Instant::now())?;
+                if exited.is_some() {
+                    for event in rx.try_iter() {
+                        if let Event::Data(stream, source, data) = event {
+                            collector.push(stream, source, &data);
+                        }
+                    }
+                    collector.finish();
+                    break;
+                }
+                return Err(timed_out(
+                    &mut children,
+                    pgid,
+                    invocations,
+                    opts.grace_period,
+                    &rx,
+                    collector,
+                ));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+        }
+
+        let (stdout, stderr) = (&collector.stdout, &collector.stderr);
+        if opts.limit_policy == LimitPolicy::Kill && (stdout.truncated || stderr.truncated) {
+            kill_all(&mut children, pgid)?;
+            let limit = match stdout.truncated {
+                true => stdout.limit,
+                false => stderr.limit,
+            };
+            let mut invocation = Box::new(invocations.pop().unwrap());
+            invocation.duration = start.elapsed();
+            return Err(Error::OutputLimitExceeded(
+                collector.output().into(),
+                limit.unwrap_or_default(),
+                invocation,
+            ));
+        }
+    }
+
+    let statuses = loop {
+        if let Some(statuses) = exited {
+            break statuses;
+        }
+        let statuses = match opts.next_check(deadline) {
+            None => Some(
+                children
+                    .iter_mut()
+                    .map(process::Child::wait)
+                    .collect::<io::Result<Vec<_>>>()?,
+            ),
+            Some(until) => wait_until(&mut children, until)?,
+        };
+        if let Some(statuses) = statuses {
+            break statuses;
+        }
+        if opts.cancelled() {
+            kill_all(&mut children, pgid)?;
+            return Err(Error::Cancelled);
+        }
+        if expired(deadline) {
+            return Err(timed_out(
+                &mut children,
+                pgid,
+                invocations,
+                opts.grace_period,
+                &rx,
+                collector,
+            ));
+        }
+    };
+
+    Ok(Finished {
+        statuses,
+        output: collector.output(),
+        invocations,
+        duration: start.elapsed(),
+    })
+}
+
+fn forward<R: Read + Send + 'static>(
+    mut reader: R,
+    stream: Stream,
+    source: usize,
+    tx: Sender<Event>,
+) {
+    thread::spawn(move || {
+        let mut buf = [0; 8192];
+        let res = loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(n) => {
+                    if tx
+                        .send(Event::Data(stream, source, buf[.. n].to_vec()))
+                        .is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => break Err(e),
+            }
+        };
+        let _ = tx.send(Event::Done(stream, source, res));
+    });
+}
+
+fn next_event(
+    rx: &Receiver<Event>,
+    until: Option<Instant>,
+) -> std::result::Result<Event, RecvTimeoutError> {
+    match until {
+        None => rx.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        Some(until) => rx.recv_timeout(until.saturating_duration_since(Instant::now())),
+    }
+}
+
+/// Waits for every child to exit, returning `None` if any is still
+/// running once the deadline is reached.
+fn wait_until(
+    children: &mut [process::Child],
+    deadline: Instant,
+) -> io::Result<Option<Vec<ExitStatus>>> {
+    loop {
+        let mut statuses = Vec::with_capacity(children.len());
+        for child in children.iter_mut() {
+            match child.try_wait()? {
+                Some(status) => statuses.push(status),
+                None => break,
+            }
+        }
+        if statuses.len() == children.len() {
+            return Ok(Some(statuses));
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
+}
+
+fn timed_out(
+    children: &mut [process::Child],
+    pgid: Option<u32>,
+    mut invocations: Vec<Invocation>,
+    grace_period: Duration,
+    rx: &Receiver<Event>,
+    mut collector: Collector<'_>,
+) -> Error {
+    let running = match children
+        .iter_mut()
+        .map(process::Child::try_wait)
+        .collect::<io::Result<Vec<_>>>()
+    {
+        Ok(statuses) => statuses.iter().position(Option::is_none),
+        Err(e) => return Error::Io(e),
+    };
+    if let Err(e) = terminate(children, pgid, grace_period) {
+        return Error::Io(e);
+    }
+
+    // collect what was written before the process was terminated
+    for event in rx.try_iter() {
+        if let Event::Data(stream, source, data) = event {
+            collector.push(stream, source, &data);
+        }
+    }
+
+    let elapsed = collector.start.elapsed();
+    let mut invocation = Box::new(invocations.swap_remove(running.unwrap_or(0)));
+    invocation.duration = elapsed;
+
+    Error::Timeout(collector.output().into(), elapsed, invocation)
+}
+
+/// Asks the children to terminate with `SIGTERM`, killing the ones
+/// still running after the grace period.
+///
+/// When the children have their own process group, the whole group is
+/// signaled instead, being killed after the grace period even if the
+/// children exited so no process of the tree is left behind.
+#[cfg(unix)]
+fn terminate(
+    children: &mut [process::Child],
+    pgid: Option<u32>,
+    grace_period: Duration,
+) -> io::Result<()> {
+    use nix::{
+        sys::signal::{kill, Signal},
+        unistd::Pid,
+    };
+
+    match pgid {
+        Some(pgid) => signal_group(pgid, Signal::SIGTERM)?,
+        None => {
+            for child in children.iter_mut() {
+                if child.try_wait()?.is_none() {
+                    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM)?;
+                }
+            }
+        }
+    }
+    match wait_until(children, Instant::now() + grace_period)? {
+        None => kill_all(children, pgid)?,
+        Some(_) => {
+            if let Some(pgid) = pgid {
+                signal_group(pgid, Signal::SIGKILL)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Windows has no graceful termination request so the children are
+/// killed right away.
+#[cfg(windows)]
+fn terminate(
+    children: &mut [process::Child],
+    pgid: Option<u32>,
+    _grace_period: Duration,
+) -> io::Result<()> {
+    kill_all(children, pgid)
+}
+
+/// Sends the signal to every process of the group, ignoring a group
+/// which no longer exists.
+#[cfg(unix)]
+pub(crate) fn signal_group(pgid: u32, signal: nix::sys::signal::Signal) -> io::Result<()> {
+    use nix::{errno::Errno, sys::signal::killpg, unistd::Pid};
+
+    match killpg(Pid::from_raw(pgid as i32), signal) {
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Kills the children, and their process group when they have their
+/// own, waiting for them to exit.
+#[cfg_attr(windows, allow(unused_variables))]
+pub(crate) fn kill_all(children: &mut [process::Child], pgid: Option<u32>) -> io::Result<()> {
+    #[cfg(unix)]
+    if let Some(pgid) = pgid {
+        signal_group(pgid, nix::sys::signal::Signal::SIGKILL)?;
+    }
+    for child in children.iter_mut() {
+        if child.try_wait()?.is_none() {
+            child.kill()?;
+            child.wait()?;
+        }
+    }
+
+    Ok(())
+}
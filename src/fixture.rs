@@ -0,0 +1,401 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Record and replay of commands, so code calling `easy_process::run`
+//! and `easy_process::run_with_stdin` can be tested without the real
+//! programs.
+
+use crate::{
+    invocations, setup_process,
+    status::{status_from_raw, status_to_raw},
+    Finished, RawOutput, Result,
+};
+use std::{
+    cell::RefCell,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{ChildStdin, ExitStatus},
+    thread,
+    time::Duration,
+};
+
+thread_local! {
+    static ACTIVE: RefCell<Option<Active>> = const { RefCell::new(None) };
+}
+
+#[derive(Debug)]
+enum Active {
+    Record(File),
+    Replay {
+        path: PathBuf,
+        entries: Vec<Entry>,
+        served: Vec<bool>,
+    },
+}
+
+/// A recorded command.
+#[derive(Debug)]
+struct Entry {
+    cmd: String,
+    stdin: Vec<u8>,
+    output: RawOutput,
+    statuses: Vec<ExitStatus>,
+}
+
+/// Records or replays the commands run by the current thread while it
+/// is alive.
+///
+/// While recording, the commands are run as usual and the command
+/// line, stdin, output and exit status of each of them are written to
+/// the fixture file. While replaying, the results are served from the
+/// fixture file without spawning any process, panicking when a command
+/// was not recorded.
+///
+/// Only `easy_process::run` and `easy_process::run_with_stdin` are
+/// intercepted, and only on the thread which created the `Fixture`.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// let _fixture = easy_process::Fixture::replay("tests/fixtures/uname.txt")?;
+/// let output = easy_process::run("uname -s")?;
+/// assert_eq!(&output.stdout, "Linux\n");
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+#[must_use = "commands are only recorded or replayed while the fixture is alive"]
+pub struct Fixture {
+    _private: (),
+}
+
+impl Fixture {
+    /// Starts recording the commands to the given fixture file,
+    /// replacing its contents.
+    pub fn record<P: AsRef<Path>>(path: P) -> io::Result<Fixture> {
+        Fixture::activate(|| Ok(Active::Record(File::create(path)?)))
+    }
+
+    /// Starts replaying the commands recorded in the given fixture
+    /// file.
+    pub fn replay<P: AsRef<Path>>(path: P) -> io::Result<Fixture> {
+        let path = path.as_ref().to_path_buf();
+        Fixture::activate(|| {
+            let entries = parse(&fs::read(&path)?)?;
+            Ok(Active::Replay {
+                path,
+                served: vec![false; entries.len()],
+                entries,
+            })
+        })
+    }
+
+    /// Activates the fixture built by `active`, which is only called
+    /// when no other fixture is active so the file is left untouched
+    /// otherwise.
+    fn activate<F>(active: F) -> io::Result<Fixture>
+    where
+        F: FnOnce() -> io::Result<Active>,
+    {
+        ACTIVE.with(|a| {
+            let mut a = a.borrow_mut();
+            if a.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "a fixture is already active in this thread",
+                ));
+            }
+            *a = Some(active()?);
+
+            Ok(Fixture { _private: () })
+        })
+    }
+}
+
+impl Drop for Fixture {
+    fn drop(&mut self) {
+        ACTIVE.with(|a| a.borrow_mut().take());
+    }
+}
+
+/// Whether a fixture is active in the current thread.
+pub(crate) fn active() -> bool {
+    ACTIVE.with(|a| a.borrow().is_some())
+}
+
+/// Records or replays the command when a fixture is active, using
+/// `run` to actually run it while recording. Returns `None` when no
+/// fixture is active.
+pub(crate) fn intercept<F>(cmd: &str, stdin: Vec<u8>, run: F) -> Option<Result<Finished>>
+where
+    F: FnOnce(Vec<u8>) -> Result<Finished>,
+{
+    let replaying = ACTIVE.with(|a| match a.borrow().as_ref() {
+        None => None,
+        Some(Active::Record(_)) => Some(false),
+        Some(Active::Replay { .. }) => Some(true),
+    })?;
+    if replaying {
+        return Some(replay(cmd, &stdin));
+    }
+
+    let finished = match run(stdin.clone()) {
+        Ok(finished) => finished,
+        Err(e) => return Some(Err(e)),
+    };
+    let entry = Entry {
+        cmd: cmd.to_string(),
+        stdin,
+        output: finished.output.clone(),
+        statuses: finished.statuses.clone(),
+    };
+    let written = ACTIVE.with(|a| match a.borrow_mut().as_mut() {
+        Some(Active::Record(file)) => file.write_all(&serialize(&entry)),
+        _ => Ok(()),
+    });
+
+    Some(written.map(|_| finished).map_err(Into::into))
+}
+
+fn replay(cmd: &str, stdin: &[u8]) -> Result<Finished> {
+    let (statuses, output, path) = ACTIVE
+        .with(|a| match a.borrow_mut().as_mut() {
+            Some(Active::Replay {
+                path,
+                entries,
+                served,
+            }) => {
+                // the recorded runs of the same command are served in
+                // order, repeating the last one once all were served
+                let matching = (0 .. entries.len())
+                    .filter(|&i| entries[i].cmd == cmd && entries[i].stdin == stdin)
+                    .collect::<Vec<_>>();
+                let i = matching
+                    .iter()
+                    .find(|&&i| !served[i])
+                    .or_else(|| matching.last())
+                    .copied();
+                match i {
+                    Some(i) => {
+                        served[i] = true;
+                        let e = &entries[i];
+                        Ok((e.statuses.clone(), e.output.clone(), path.clone()))
+                    }
+                    None => Err(path.clone()),
+                }
+            }
+            _ => unreachable!("replay without an active fixture"),
+        })
+        .unwrap_or_else(|path| {
+            panic!(
+                "command {:?} with stdin {:?} is not recorded in {:?}",
+                cmd,
+                String::from_utf8_lossy(stdin),
+                path
+            )
+        });
+
+    let invocations = invocations(&setup_process(cmd, None)?);
+    if invocations.len() != statuses.len() {
+        panic!("command {:?} does not match its record in {:?}", cmd, path);
+    }
+
+    Ok(Finished {
+        statuses,
+        output,
+        invocations,
+        duration: Duration::default(),
+    })
+}
+
+/// Calls `f` with a stdin handle which is not connected to any process,
+/// returning what was written to it.
+pub(crate) fn capture_stdin<F, E>(f: F) -> std::result::Result<Vec<u8>, E>
+where
+    F: FnOnce(&mut ChildStdin) -> std::result::Result<(), E>,
+    E: From<crate::Error>,
+{
+    let (mut reader, writer) = os_pipe::pipe().map_err(crate::Error::from)?;
+    let collector = thread::spawn(move || {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).map(|_| data)
+    });
+
+    let mut stdin = child_stdin(writer);
+    let res = f(&mut stdin);
+    drop(stdin);
+    let data = collector
+        .join()
+        .expect("stdin collector panicked")
+        .map_err(crate::Error::from)?;
+
+    res.map(|_| data)
+}
+
+#[cfg(unix)]
+pub(crate) fn child_stdin(writer: os_pipe::PipeWriter) -> ChildStdin {
+    ChildStdin::from(std::os::unix::io::OwnedFd::from(writer))
+}
+
+#[cfg(windows)]
+pub(crate) fn child_stdin(writer: os_pipe::PipeWriter) -> ChildStdin {
+    ChildStdin::from(std::os::windows::io::OwnedHandle::from(writer))
+}
+
+/// Serializes the entry as length prefixed fields, keeping the data
+/// byte-exact while still readable:
+///
+/// ```text
+/// command 7
+/// uname -s
+/// stdin 0
+///
+/// stdout 6
+/// Linux
+///
+/// stderr 0
+///
+/// status 0
+/// ```
+fn serialize(entry: &Entry) -> Vec<u8> {
+    let mut out = Vec::new();
+    for (name, data) in [
+        ("command", entry.cmd.as_bytes()),
+        ("stdin", &entry.stdin),
+        ("stdout", &entry.output.stdout),
+        ("stderr", &entry.output.stderr),
+    ] {
+        out.extend_from_slice(format!("{} {}\n", name, data.len()).as_bytes());
+        out.extend_from_slice(data);
+        out.push(b'\n');
+    }
+    let statuses = entry
+        .statuses
+        .iter()
+        .map(|s| status_to_raw(s).to_string())
+        .collect::<Vec<_>>();
+    out.extend_from_slice(format!("status {}\n\n", statuses.join(" ")).as_bytes());
+
+    out
+}
+
+fn parse(data: &[u8]) -> io::Result<Vec<Entry>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut rest = data;
+    let line = |rest: &mut &[u8]| -> io::Result<String> {
+        let end = rest
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(|| invalid("truncated fixture"))?;
+        let line = String::from_utf8(rest[.. end].to_vec()).map_err(|_| invalid("invalid line"))?;
+        *rest = &rest[end + 1 ..];
+        Ok(line)
+    };
+    let field = |rest: &mut &[u8], name: &str| -> io::Result<Vec<u8>> {
+        let header = line(rest)?;
+        let len = header
+            .strip_prefix(name)
+            .and_then(|l| l.strip_prefix(' '))
+            .and_then(|l| l.parse::<usize>().ok())
+            .ok_or_else(|| invalid(&format!("expected {:?} field", name)))?;
+        if rest.len() <= len || rest[len] != b'\n' {
+            return Err(invalid("truncated fixture"));
+        }
+        let data = rest[.. len].to_vec();
+        *rest = &rest[len + 1 ..];
+        Ok(data)
+    };
+
+    let mut entries = Vec::new();
+    while !rest.is_empty() {
+        let cmd = String::from_utf8(field(&mut rest, "command")?)
+            .map_err(|_| invalid("invalid command"))?;
+        let stdin = field(&mut rest, "stdin")?;
+        let stdout = field(&mut rest, "stdout")?;
+        let stderr = field(&mut rest, "stderr")?;
+        let statuses = line(&mut rest)?
+            .strip_prefix("status ")
+            .ok_or_else(|| invalid("expected \"status\" field"))?
+            .split(' ')
+            .map(|s| s.parse().ok().and_then(status_from_raw))
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| invalid("invalid status"))?;
+        if !line(&mut rest)?.is_empty() {
+            return Err(invalid("expected end of entry"));
+        }
+
+        entries.push(Entry {
+            cmd,
+            stdin,
+            output: RawOutput {
+                stdout,
+                stderr,
+                ..RawOutput::default()
+            },
+            statuses,
+        });
+    }
+
+    Ok(entries)
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::{run, run_with_stdin, Error};
+
+    #[test]
+    fn record_and_replay() {
+        let path =
+            std::env::temp_dir().join(format!("easy-process-fixture-{}", std::process::id()));
+
+        {
+            let _fixture = Fixture::record(&path).unwrap();
+            assert_eq!(&run("echo hello").unwrap().stdout, "hello\n");
+            assert!(matches!(
+                run("sh -c 'echo err >&2; exit 3'"),
+                Err(Error::Failure(..))
+            ));
+            let output = run_with_stdin("rev", |stdin| {
+                Write::write_all(stdin, b"abc\n")?;
+                Result::Ok(())
+            })
+            .unwrap();
+            assert_eq!(&output.stdout, "cba\n");
+        }
+
+        let _fixture = Fixture::replay(&path).unwrap();
+        assert_eq!(&run("echo hello").unwrap().stdout, "hello\n");
+        match run("sh -c 'echo err >&2; exit 3'") {
+            Err(e @ Error::Failure(..)) => {
+                assert_eq!(e.exit(), Some(crate::ExitKind::Exited(3)));
+                assert_eq!(e.invocation().unwrap().program, "sh");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        let output = run_with_stdin("rev", |stdin| {
+            Write::write_all(stdin, b"abc\n")?;
+            Result::Ok(())
+        })
+        .unwrap();
+        assert_eq!(&output.stdout, "cba\n");
+
+        // only one fixture can be active at a time, the file being kept
+        let recorded = fs::read(&path).unwrap();
+        assert!(Fixture::record(&path).is_err());
+        assert_eq!(fs::read(&path).unwrap(), recorded);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "is not recorded")]
+    fn unrecorded() {
+        let path = std::env::temp_dir().join(format!("easy-process-empty-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let _fixture = Fixture::replay(&path).unwrap();
+        let _ = fs::remove_file(&path);
+        let _ = run("echo hello");
+    }
+}
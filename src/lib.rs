@@ -32,9 +32,61 @@
 //! didn't succeed they will return a `Err(...)` instead of a
 //! `Ok(...)`.
 //!
+//! Unquoted `|` characters split the command line in pipeline
+//! stages, which are connected through pipes. A pipeline fails if any
+//! of its stages does not succeed, reporting the last one which
+//! failed.
+//!
+//! The standard streams of each stage can be redirected with the
+//! unquoted `<`, `>`, `>>`, `2>`, `2>>` and `2>&1` operators. Relative
+//! paths are resolved against the working directory of the command.
+//! [`quote`] does the inverse, quoting an argv into a command line
+//! string.
+//!
 //! Note that the provided functions do return their own `Output`
 //! struct instead of [`std::process::Output`].
 //!
+//! When more control is needed, such as setting the working directory
+//! or environment variables, the [`Command`] builder can be used.
+//!
+//! Commands failing transiently can be retried with [`Retry`] and
+//! many independent commands can be run concurrently with [`Pool`].
+//!
+//! A dry-run mode, enabled with [`set_dry_run`] or
+//! [`Command::dry_run`], logs the commands instead of running them.
+//!
+//! Code calling these functions can be tested without the real
+//! programs by recording their results with a [`Fixture`] and
+//! replaying them later.
+//!
+//! On Linux, `Command::pty` runs the command attached to a
+//! pseudo-terminal, for programs which behave differently when not
+//! writing to a terminal.
+//!
+//! Secrets registered with [`redact`] are masked in the errors, logs
+//! and traces showing the commands and their output. Enabling the
+//! `regex` feature provides `redact_regex`, which masks the secrets
+//! matching a pattern.
+//!
+//! On Unix, `Command::process_group` isolates the command in its own
+//! process group, `Command::rlimit` limits the resources it may use
+//! and `Command::uid` runs it as a different user.
+//!
+//! Enabling the `tracing` feature emits a `tracing` span for every
+//! executed command, recording its argv, pid, cwd, duration and exit
+//! status.
+//!
+//! Enabling the `serde` feature provides `run_json` and
+//! `Command::run_json`, which parse the stdout of the command as
+//! JSON.
+//!
+//! Enabling the `macros` feature provides the `cmd!` macro, which
+//! splits a literal command line at compile time and interpolates
+//! values as single arguments.
+//!
+//! Enabling the `tokio` feature provides asynchronous versions of
+//! these functions in the `easy_process::tokio` module.
+//!
 //! # Example
 //! ```no_run
 //! # fn run() -> Result<(), easy_process::Error> {
@@ -60,60 +112,408 @@
 //! # }
 //! ```
 
-use cmdline_words_parser::parse_posix;
+// lets the code generated by `cmd!` refer to `::easy_process` in the
+// tests of the crate itself
+#[cfg(feature = "macros")]
+extern crate self as easy_process;
+
+mod command;
+#[cfg(unix)]
+mod credentials;
+mod dry_run;
+mod exec;
+mod fixture;
+mod parser;
+mod pool;
+#[cfg(target_os = "linux")]
+mod pty;
+mod redact;
+mod retry;
+mod rlimit;
+mod stage;
+mod status;
+#[cfg(feature = "tokio")]
+pub mod tokio;
+mod trace;
+
+#[cfg(feature = "regex")]
+pub use crate::redact::redact_regex;
+pub use crate::{
+    command::{Child, Command},
+    dry_run::set_dry_run,
+    exec::{LimitPolicy, Stream},
+    fixture::Fixture,
+    parser::{quote, ParseError},
+    pool::Pool,
+    redact::{redact, Redaction},
+    retry::{Attempt, Backoff, Retry},
+    rlimit::Rlimit,
+    status::ExitKind,
+};
+#[cfg(feature = "macros")]
+pub use easy_process_macros::cmd;
+
+use crate::{parser::Vars, redact::Masked, stage::Stage};
 use derive_more::{Display, Error, From};
 use std::{
-    io,
-    process::{ChildStdin, ExitStatus, Stdio},
+    env, fmt, io,
+    path::PathBuf,
+    process::{self, ChildStdin, ExitStatus, Stdio},
+    thread,
+    time::Duration,
 };
 
-#[derive(Debug, Default)]
+#[derive(Default, Clone)]
 /// Holds the output for a giving `easy_process::run`
 pub struct Output {
     /// The stdout output of the process
     pub stdout: String,
     /// The stderr output of the process
     pub stderr: String,
+    /// Whether stdout was truncated for exceeding its size limit
+    pub stdout_truncated: bool,
+    /// Whether stderr was truncated for exceeding its size limit
+    pub stderr_truncated: bool,
+    /// The chunks of both streams in the order they were written, when
+    /// requested with [`Command::transcript`]
+    pub transcript: Vec<Chunk>,
+}
+
+#[derive(Default, Clone)]
+/// Holds the byte-exact output for a giving `easy_process::run_bytes`
+pub struct RawOutput {
+    /// The stdout output of the process
+    pub stdout: Vec<u8>,
+    /// The stderr output of the process
+    pub stderr: Vec<u8>,
+    /// Whether stdout was truncated for exceeding its size limit
+    pub stdout_truncated: bool,
+    /// Whether stderr was truncated for exceeding its size limit
+    pub stderr_truncated: bool,
+    /// The chunks of both streams in the order they were written, when
+    /// requested with [`Command::transcript`]
+    pub transcript: Vec<Chunk>,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+/// A piece of output of a process, as read from one of its streams
+pub struct Chunk {
+    /// The stream the data was written to
+    pub stream: Stream,
+    /// The time elapsed since the process was spawned when the data
+    /// was read
+    pub elapsed: Duration,
+    /// The data, which may end in the middle of a line or of a UTF-8
+    /// sequence
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for Output {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Output")
+            .field("stdout", &Masked::from(self.stdout.as_str()))
+            .field("stderr", &Masked::from(self.stderr.as_str()))
+            .field("stdout_truncated", &self.stdout_truncated)
+            .field("stderr_truncated", &self.stderr_truncated)
+            .field("transcript", &self.transcript)
+            .finish()
+    }
+}
+
+impl fmt::Debug for RawOutput {
+    /// Shows the output converted to UTF-8, so the secrets can be
+    /// masked.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawOutput")
+            .field("stdout", &Masked::bytes(&self.stdout))
+            .field("stderr", &Masked::bytes(&self.stderr))
+            .field("stdout_truncated", &self.stdout_truncated)
+            .field("stderr_truncated", &self.stderr_truncated)
+            .field("transcript", &self.transcript)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Chunk {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Chunk")
+            .field("stream", &self.stream)
+            .field("elapsed", &self.elapsed)
+            .field("data", &Masked::bytes(&self.data))
+            .finish()
+    }
+}
+
+impl From<RawOutput> for Output {
+    /// Converts the output to UTF-8, replacing invalid sequences with
+    /// `U+FFFD REPLACEMENT CHARACTER`. A truncated stream is cut at the
+    /// last character boundary instead.
+    fn from(o: RawOutput) -> Self {
+        Output {
+            stdout: text(&o.stdout, o.stdout_truncated),
+            stderr: text(&o.stderr, o.stderr_truncated),
+            stdout_truncated: o.stdout_truncated,
+            stderr_truncated: o.stderr_truncated,
+            transcript: o.transcript,
+        }
+    }
+}
+
+/// Converts the output to UTF-8, dropping the incomplete sequence left
+/// at the end when the output was truncated.
+fn text(data: &[u8], truncated: bool) -> String {
+    let data = match std::str::from_utf8(data) {
+        Err(e) if truncated && e.error_len().is_none() => &data[.. e.valid_up_to()],
+        _ => data,
+    };
+
+    String::from_utf8_lossy(data).to_string()
+}
+
+impl From<Output> for RawOutput {
+    fn from(o: Output) -> Self {
+        RawOutput {
+            stdout: o.stdout.into_bytes(),
+            stderr: o.stderr.into_bytes(),
+            stdout_truncated: o.stdout_truncated,
+            stderr_truncated: o.stderr_truncated,
+            transcript: o.transcript,
+        }
+    }
+}
+
+#[derive(Default, Clone)]
+/// Describes how a process was run, to help diagnosing its failures
+pub struct Invocation {
+    /// The program which was run
+    pub program: String,
+    /// The arguments given to the program
+    pub args: Vec<String>,
+    /// The working directory of the process
+    pub cwd: Option<PathBuf>,
+    /// The wall-clock time the process took
+    pub duration: Duration,
+    /// The resources limited with `Command::rlimit`
+    pub rlimits: Vec<Rlimit>,
+}
+
+impl Invocation {
+    fn new(stage: &Stage) -> Invocation {
+        let cmd = &stage.cmd;
+        Invocation {
+            program: cmd.get_program().to_string_lossy().to_string(),
+            args: cmd
+                .get_args()
+                .map(|a| a.to_string_lossy().to_string())
+                .collect(),
+            cwd: cmd
+                .get_current_dir()
+                .map(|d| d.to_path_buf())
+                .or_else(|| env::current_dir().ok()),
+            duration: Duration::default(),
+            rlimits: stage.rlimits.clone(),
+        }
+    }
+
+    /// Classifies the exit status of the process, taking into account
+    /// the resources it had limited.
+    fn exit(&self, status: ExitStatus) -> ExitKind {
+        ExitKind::new(status, &self.rlimits)
+    }
+}
+
+impl fmt::Display for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "command: {:?}", Masked::from(self.program.as_str()))?;
+        for arg in &self.args {
+            write!(f, " {:?}", Masked::from(arg.as_str()))?;
+        }
+        if let Some(cwd) = &self.cwd {
+            write!(f, " cwd: {:?}", Masked(cwd.to_string_lossy()))?;
+        }
+        write!(f, " duration: {:?}", self.duration)
+    }
+}
+
+impl fmt::Debug for Invocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Invocation")
+            .field("program", &Masked::from(self.program.as_str()))
+            .field(
+                "args",
+                &self
+                    .args
+                    .iter()
+                    .map(|a| Masked::from(a.as_str()))
+                    .collect::<Vec<_>>(),
+            )
+            .field(
+                "cwd",
+                &self.cwd.as_ref().map(|c| Masked(c.to_string_lossy())),
+            )
+            .field("duration", &self.duration)
+            .field("rlimits", &self.rlimits)
+            .finish()
+    }
 }
 
 /// Error variant for `easy_process::run`.
+///
+/// New variants may be added, some of them only with a feature
+/// enabled, so matches must include a wildcard arm.
 #[derive(Display, Error, From, Debug)]
+#[non_exhaustive]
 pub enum Error {
     /// I/O error
     #[display(fmt = "unexpected I/O Error: {}", _0)]
     Io(io::Error),
-    /// Process error. It holds two parts: first argument is the exit
-    /// code and the second is the output (stdout and stderr).
+    /// Process error. It holds three parts: first argument is the exit
+    /// code, the second is the output (stdout and stderr) and the third
+    /// describes how the process was run.
+    #[display(
+        fmt = "{} status: {} stdout: {:?} stderr: {:?}",
+        _2,
+        "_2.exit(*_0)",
+        "Masked::from(_1.stdout.as_str())",
+        "Masked::from(_1.stderr.as_str())"
+    )]
+    Failure(ExitStatus, Output, Box<Invocation>),
+    /// Pipeline error. It holds the index of the stage which failed,
+    /// being the last one with a non successful exit status, its exit
+    /// code, the output (stdout and stderr) and how the failed stage
+    /// was run.
+    #[display(
+        fmt = "stage: {} {} status: {} stdout: {:?} stderr: {:?}",
+        _0,
+        _3,
+        "_3.exit(*_1)",
+        "Masked::from(_2.stdout.as_str())",
+        "Masked::from(_2.stderr.as_str())"
+    )]
+    #[from(ignore)]
+    PipelineFailure(usize, ExitStatus, Output, Box<Invocation>),
+    /// Process error for `easy_process::run_bytes`. It holds the exit
+    /// code, the byte-exact output (stdout and stderr) and how the
+    /// process was run.
+    #[display(
+        fmt = "{} status: {} stdout: {:?} stderr: {:?}",
+        _2,
+        "_2.exit(*_0)",
+        "Masked::bytes(&_1.stdout)",
+        "Masked::bytes(&_1.stderr)"
+    )]
+    #[from(ignore)]
+    RawFailure(ExitStatus, RawOutput, Box<Invocation>),
+    /// Pipeline error for `easy_process::run_bytes`. It holds the same
+    /// as `PipelineFailure`, with the output kept byte-exact.
+    #[display(
+        fmt = "stage: {} {} status: {} stdout: {:?} stderr: {:?}",
+        _0,
+        _3,
+        "_3.exit(*_1)",
+        "Masked::bytes(&_2.stdout)",
+        "Masked::bytes(&_2.stderr)"
+    )]
+    #[from(ignore)]
+    RawPipelineFailure(usize, ExitStatus, RawOutput, Box<Invocation>),
+    /// Command line parsing error
+    #[display(fmt = "unable to parse command: {}", _0)]
+    Parse(ParseError),
+    /// Timeout error. It holds the output captured until the process
+    /// was terminated, the elapsed time since it was spawned and how
+    /// the process was run. For pipelines it refers to the first stage
+    /// which was still running.
+    #[display(
+        fmt = "timed out after {:?} {} stdout: {:?} stderr: {:?}",
+        _1,
+        _2,
+        "Masked::from(_0.stdout.as_str())",
+        "Masked::from(_0.stderr.as_str())"
+    )]
+    #[from(ignore)]
+    Timeout(Output, Duration, Box<Invocation>),
+    /// Output limit error, returned when a stream exceeds its size
+    /// limit under [`LimitPolicy::Kill`]. It holds the output captured
+    /// until the process was killed, the exceeded limit in bytes and
+    /// how the process was run. For pipelines it refers to the last
+    /// stage.
+    #[display(
+        fmt = "output limit of {} bytes exceeded {} stdout: {:?} stderr: {:?}",
+        _1,
+        _2,
+        "Masked::from(_0.stdout.as_str())",
+        "Masked::from(_0.stderr.as_str())"
+    )]
+    #[from(ignore)]
+    OutputLimitExceeded(Output, usize, Box<Invocation>),
+    /// Error returned by [`Retry`] when the attempts are exhausted. It
+    /// holds the error of the last attempt and the previous attempts.
+    #[display(fmt = "gave up after {} attempts: {}", "_1.len() + 1", _0)]
+    #[from(ignore)]
+    RetriesExhausted(#[error(source)] Box<Error>, Vec<Attempt>),
+    /// JSON parsing error for `easy_process::run_json`, returned when
+    /// the command succeeded but its stdout could not be parsed. It
+    /// holds the parse error, which reports the line and column where
+    /// parsing failed, the whole output (stdout and stderr) and how the
+    /// process was run.
+    #[cfg(feature = "serde")]
     #[display(
-        fmt = "status: {:?} stdout: {:?} stderr: {:?}",
-        "_0.code()",
-        "_1.stdout",
-        "_1.stderr"
+        fmt = "unable to parse JSON output: {} {} stdout: {:?} stderr: {:?}",
+        _0,
+        _2,
+        "Masked::from(_1.stdout.as_str())",
+        "Masked::from(_1.stderr.as_str())"
     )]
-    Failure(ExitStatus, Output),
+    #[from(ignore)]
+    Json(#[error(source)] serde_json::Error, Output, Box<Invocation>),
+    /// The command was not run, or was killed, because an earlier
+    /// command of a fail fast [`Pool`] failed.
+    #[display(fmt = "cancelled after an earlier command failed")]
+    #[from(ignore)]
+    Cancelled,
 }
 
-/// Result alias with crate's Error value
-pub type Result<T> = std::result::Result<T, Error>;
+impl Error {
+    /// Returns how the process which caused the error terminated, for
+    /// errors caused by a non successful exit status.
+    pub fn exit(&self) -> Option<ExitKind> {
+        match self {
+            Error::Failure(ex, _, i)
+            | Error::PipelineFailure(_, ex, _, i)
+            | Error::RawFailure(ex, _, i)
+            | Error::RawPipelineFailure(_, ex, _, i) => Some(i.exit(*ex)),
+            Error::RetriesExhausted(last, _) => last.exit(),
+            Error::Io(_)
+            | Error::Parse(_)
+            | Error::Timeout(..)
+            | Error::OutputLimitExceeded(..)
+            | Error::Cancelled => None,
+            #[cfg(feature = "serde")]
+            Error::Json(..) => None,
+        }
+    }
 
-impl From<checked_command::Error> for Error {
-    fn from(error: checked_command::Error) -> Self {
-        match error {
-            checked_command::Error::Io(e) => Error::Io(e),
-            checked_command::Error::Failure(ex, err) => Error::Failure(
-                ex,
-                match err {
-                    Some(e) => Output {
-                        stdout: String::from_utf8_lossy(&e.stdout).to_string(),
-                        stderr: String::from_utf8_lossy(&e.stderr).to_string(),
-                    },
-                    None => Output::default(),
-                },
-            ),
+    /// Returns how the process which caused the error was run, if
+    /// known.
+    pub fn invocation(&self) -> Option<&Invocation> {
+        match self {
+            Error::Failure(_, _, i)
+            | Error::PipelineFailure(_, _, _, i)
+            | Error::RawFailure(_, _, i)
+            | Error::RawPipelineFailure(_, _, _, i)
+            | Error::Timeout(_, _, i)
+            | Error::OutputLimitExceeded(_, _, i) => Some(i),
+            #[cfg(feature = "serde")]
+            Error::Json(_, _, i) => Some(i),
+            Error::RetriesExhausted(last, _) => last.invocation(),
+            Error::Io(_) | Error::Parse(_) | Error::Cancelled => None,
         }
     }
 }
 
+/// Result alias with crate's Error value
+pub type Result<T> = std::result::Result<T, Error>;
+
 /// Runs the given command
 ///
 /// # Arguments
@@ -124,13 +524,99 @@ impl From<checked_command::Error> for Error {
 ///
 /// if the exit status is not successful or a `io::Error` was returned.
 pub fn run(cmd: &str) -> Result<Output> {
-    let mut cmd = setup_process(cmd);
+    match fixture::intercept(cmd, Vec::new(), |_| Command::new(cmd).run_finished()) {
+        Some(finished) => checked_output(finished?),
+        None => Command::new(cmd).run(),
+    }
+}
 
-    let o = cmd.output()?;
-    Ok(Output {
-        stdout: String::from_utf8_lossy(&o.stdout).to_string(),
-        stderr: String::from_utf8_lossy(&o.stderr).to_string(),
-    })
+/// Runs the given command, parsing its stdout as JSON
+///
+/// # Arguments
+///
+/// `cmd` - A string slice containing the command to be run.
+///
+/// # Errors
+///
+/// if the exit status is not successful, stdout is not valid JSON for
+/// `T`, in which case `Error::Json` is returned, or a `io::Error` was
+/// returned.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// #[derive(serde::Deserialize)]
+/// struct Device {
+///     name: String,
+/// }
+/// #[derive(serde::Deserialize)]
+/// struct Devices {
+///     blockdevices: Vec<Device>,
+/// }
+///
+/// let devices: Devices = easy_process::run_json("lsblk --json")?;
+/// # Ok(())
+/// # }
+/// ```
+#[cfg(feature = "serde")]
+pub fn run_json<T: serde::de::DeserializeOwned>(cmd: &str) -> Result<T> {
+    match fixture::intercept(cmd, Vec::new(), |_| Command::new(cmd).run_finished()) {
+        Some(finished) => checked_json(finished?),
+        None => Command::new(cmd).run_json(),
+    }
+}
+
+/// Runs the given command keeping its output byte-exact
+///
+/// This is useful for commands writing binary data, which would be
+/// corrupted by the UTF-8 conversion done by `easy_process::run`.
+///
+/// # Arguments
+///
+/// `cmd` - A string slice containing the command to be run.
+///
+/// # Errors
+///
+/// if the exit status is not successful, in which case
+/// `Error::RawFailure`, or `Error::RawPipelineFailure` for pipelines,
+/// is returned, or a `io::Error` was returned.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// let output = easy_process::run_bytes("tar -cf - src")?;
+/// std::fs::write("src.tar", &output.stdout)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn run_bytes(cmd: &str) -> Result<RawOutput> {
+    Command::new(cmd).run_bytes()
+}
+
+/// Runs the given command invoking the callbacks for every line
+/// written to its stdout and stderr.
+///
+/// The lines are handed to the callbacks as they arrive, without
+/// their terminator, and the whole output is still returned once the
+/// process finishes.
+///
+/// # Examples
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// easy_process::run_streaming(
+///     "make",
+///     |line| println!("{}", line),
+///     |line| eprintln!("{}", line),
+/// )?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn run_streaming<O, E>(cmd: &str, on_stdout: O, on_stderr: E) -> Result<Output>
+where
+    O: FnMut(&str),
+    E: FnMut(&str),
+{
+    Command::new(cmd).run_streaming(on_stdout, on_stderr)
 }
 
 /// Runs command with access to it's stdin.
@@ -140,6 +626,9 @@ pub fn run(cmd: &str) -> Result<Output> {
 /// result so the users can use their locally defined error types and
 /// [`enum@Error`] itself can also be used.
 ///
+/// The stderr of the command is inherited from the current process, so
+/// it is left empty in the output.
+///
 /// # Examples
 /// ```no_run
 /// # fn run() -> Result<(), easy_process::Error> {
@@ -156,28 +645,148 @@ where
     F: FnOnce(&mut ChildStdin) -> std::result::Result<(), E>,
     E: From<Error>,
 {
-    let mut cmd = setup_process(cmd);
+    if fixture::active() {
+        let stdin = fixture::capture_stdin(f)?;
+        let finished = fixture::intercept(cmd, stdin, |stdin| {
+            // stderr is inherited as done when not recording
+            Command::new(cmd)
+                .stdin(fill_pipe(stdin)?)
+                .stderr(Stdio::inherit())
+                .run_finished()
+        })
+        .expect("fixture is active");
+        return Ok(checked_output(finished?)?);
+    }
+    if dry_run::global().is_some() {
+        // the written data is discarded as nothing is spawned
+        fixture::capture_stdin(f)?;
+        return Ok(Command::new(cmd).run()?);
+    }
+
     // both pipes must be set in order to obtain the output later
-    cmd.stdin(Stdio::piped()).stdout(Stdio::piped());
-    let mut child = cmd.spawn().map_err(Error::from)?;
-    let stdin = child.stdin().as_mut().unwrap();
+    let mut child = Command::new(cmd)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdin = match child.stdin().as_mut() {
+        Some(stdin) => stdin,
+        None => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::from(redirected_stdin()).into());
+        }
+    };
 
     f(stdin)?;
 
-    let o = child.wait_with_output().map_err(Error::from)?;
-    Ok(Output {
-        stdout: String::from_utf8_lossy(&o.stdout).to_string(),
-        stderr: String::from_utf8_lossy(&o.stderr).to_string(),
-    })
+    Ok(child.wait_with_output()?)
+}
+
+/// Error for a command which stdin cannot be written to, as its first
+/// stage reads from a file with `<`.
+fn redirected_stdin() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "stdin is redirected")
 }
 
-fn setup_process(cmd: &str) -> checked_command::CheckedCommand {
-    let mut cmd = cmd.to_string();
-    let mut args = parse_posix(&mut cmd);
+/// Returns a pipe from which the given data can be read.
+fn fill_pipe(data: Vec<u8>) -> io::Result<os_pipe::PipeReader> {
+    let (reader, mut writer) = os_pipe::pipe()?;
+    thread::spawn(move || io::Write::write_all(&mut writer, &data));
 
-    let mut p = checked_command::CheckedCommand::new(args.next().unwrap());
-    p.args(args);
-    p
+    Ok(reader)
+}
+
+fn setup_process(cmd: &str, vars: Option<&Vars>) -> Result<Vec<Stage>> {
+    Ok(parser::parse(cmd, vars)?
+        .into_iter()
+        .map(|stage| {
+            let mut p = process::Command::new(&stage.args[0]);
+            p.args(&stage.args[1 ..]);
+            Stage {
+                cmd: p,
+                redirects: stage.redirects,
+                #[cfg(unix)]
+                session: false,
+                rlimits: Vec::new(),
+            }
+        })
+        .collect())
+}
+
+/// Formats the argv of every stage, separated by `|`.
+fn command_line(invocations: &[Invocation]) -> String {
+    invocations
+        .iter()
+        .map(|i| {
+            std::iter::once(&i.program)
+                .chain(&i.args)
+                .map(|a| format!("{:?}", Masked::from(a.as_str())))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+fn invocations(stages: &[Stage]) -> Vec<Invocation> {
+    stages.iter().map(Invocation::new).collect()
+}
+
+/// A finished process, or pipeline, which exit status was not yet
+/// checked.
+struct Finished {
+    statuses: Vec<ExitStatus>,
+    output: RawOutput,
+    invocations: Vec<Invocation>,
+    duration: Duration,
+}
+
+impl Finished {
+    /// Returns the last stage which did not succeed and how it was
+    /// run.
+    fn failed_stage(&mut self) -> Option<(usize, Box<Invocation>)> {
+        let i = self.statuses.iter().rposition(|s| !s.success())?;
+        let mut invocation = Box::new(self.invocations.swap_remove(i));
+        invocation.duration = self.duration;
+        Some((i, invocation))
+    }
+}
+
+/// Checks the exit status of every pipeline stage, failing with the
+/// last one which did not succeed.
+fn checked_output(mut f: Finished) -> Result<Output> {
+    match f.failed_stage() {
+        None => Ok(f.output.into()),
+        Some((i, inv)) if f.statuses.len() == 1 => {
+            Err(Error::Failure(f.statuses[i], f.output.into(), inv))
+        }
+        Some((i, inv)) => Err(Error::PipelineFailure(
+            i,
+            f.statuses[i],
+            f.output.into(),
+            inv,
+        )),
+    }
+}
+
+/// Same as `checked_output` but parsing stdout as JSON.
+#[cfg(feature = "serde")]
+fn checked_json<T: serde::de::DeserializeOwned>(f: Finished) -> Result<T> {
+    let mut invocation = Box::new(f.invocations.last().cloned().unwrap_or_default());
+    invocation.duration = f.duration;
+    let output = checked_output(f)?;
+    serde_json::from_str(&output.stdout).map_err(|e| Error::Json(e, output, invocation))
+}
+
+/// Same as `checked_output` but keeping the output byte-exact.
+fn checked_raw_output(mut f: Finished) -> Result<RawOutput> {
+    match f.failed_stage() {
+        None => Ok(f.output),
+        Some((i, inv)) if f.statuses.len() == 1 => {
+            Err(Error::RawFailure(f.statuses[i], f.output, inv))
+        }
+        Some((i, inv)) => Err(Error::RawPipelineFailure(i, f.statuses[i], f.output, inv)),
+    }
 }
 
 #[cfg(all(test, not(windows)))]
@@ -190,10 +799,13 @@ mod tests {
         match run(r#"sh -c 'echo "error" >&2; exit 1'"#) {
             Ok(_) => panic!("call should have failed"),
             Err(Error::Io(io_err)) => panic!("unexpected I/O Error: {:?}", io_err),
-            Err(Error::Failure(ex, output)) => {
+            Err(Error::Failure(ex, output, invocation)) => {
                 assert_eq!(ex.code().unwrap(), 1);
                 assert_eq!(&output.stderr, "error\n");
+                assert_eq!(&invocation.program, "sh");
+                assert_eq!(invocation.args, ["-c", r#"echo "error" >&2; exit 1"#]);
             }
+            Err(e) => panic!("unexpected error: {:?}", e),
         }
     }
 
@@ -206,6 +818,39 @@ mod tests {
         }
     }
 
+    #[test]
+    fn failure_display() {
+        let e = run("false").unwrap_err();
+        let msg = e.to_string();
+        assert!(msg.starts_with(r#"command: "false" cwd: "#), "{}", msg);
+        assert!(msg.contains("status: exited with code 1"), "{}", msg);
+        assert!(e.invocation().unwrap().cwd.is_some());
+    }
+
+    #[test]
+    fn binary_output() {
+        let output = run_bytes(r#"printf '\377\000'"#).unwrap();
+        assert_eq!(output.stdout, [0xff, 0x00]);
+
+        match run_bytes(r#"sh -c 'printf "\377" >&2; exit 1'"#) {
+            Err(Error::RawFailure(ex, output, _)) => {
+                assert_eq!(ex.code(), Some(1));
+                assert_eq!(output.stderr, [0xff]);
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        match run_bytes(r#"sh -c 'printf "\377" >&2; exit 2' | cat"#) {
+            Err(Error::RawPipelineFailure(stage, ex, output, invocation)) => {
+                assert_eq!(stage, 0);
+                assert_eq!(ex.code(), Some(2));
+                assert_eq!(output.stderr, [0xff]);
+                assert_eq!(&invocation.program, "sh");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
     #[test]
     fn piped_input() {
         let output = run_with_stdin("rev", |stdin| {
@@ -216,6 +861,53 @@ mod tests {
         // Older versions of rev will add an new line terminator
         // so we test only the start of the stdout
         assert!(&output.stdout.starts_with("!dlrow ,olleH"));
+
+        match run_with_stdin("cat < /dev/null", |_| Result::Ok(())) {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Entry {
+            name: String,
+            size: u64,
+        }
+
+        let entries: Vec<Entry> =
+            run_json(r#"echo '[{"name": "a", "size": 1}, {"name": "b", "size": 2}]'"#).unwrap();
+        assert_eq!(
+            entries,
+            [
+                Entry {
+                    name: "a".to_string(),
+                    size: 1
+                },
+                Entry {
+                    name: "b".to_string(),
+                    size: 2
+                }
+            ]
+        );
+
+        match run_json::<Vec<Entry>>(r#"sh -c 'printf "[\n  {\"name\": 1}]"; echo oops >&2'"#) {
+            Err(Error::Json(e, output, invocation)) => {
+                assert_eq!((e.line(), e.column()), (2, 12));
+                assert_eq!(&output.stdout, "[\n  {\"name\": 1}]");
+                assert_eq!(&output.stderr, "oops\n");
+                assert_eq!(&invocation.program, "sh");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        // the exit status is checked before parsing
+        assert!(matches!(
+            Command::new("sh -c 'echo {}; exit 1'").run_json::<Vec<Entry>>(),
+            Err(Error::Failure(..))
+        ));
     }
 }
 
@@ -229,10 +921,11 @@ mod tests {
         match run(r#"powershell /C '[Console]::Error.WriteLine("Error"); exit(1)'"#) {
             Ok(_) => panic!("call should have failed"),
             Err(Error::Io(io_err)) => panic!("unexpected I/O Error: {:?}", io_err),
-            Err(Error::Failure(ex, output)) => {
+            Err(Error::Failure(ex, output, _)) => {
                 assert_eq!(ex.code().unwrap(), 1);
                 assert_eq!(&output.stderr, "Error\r\n");
             }
+            Err(e) => panic!("unexpected error: {:?}", e),
         }
     }
 
@@ -0,0 +1,554 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use derive_more::{Display, Error};
+use std::{collections::HashMap, env, iter::Peekable, str::Chars};
+
+/// Error returned when a command line string cannot be parsed.
+#[derive(Display, Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// The command line does not contain any word to be used as
+    /// program.
+    #[display(fmt = "empty command")]
+    EmptyCommand,
+    /// One of the stages of a pipeline is empty, as in `a | | b`.
+    #[display(fmt = "empty pipeline stage")]
+    EmptyStage,
+    /// A single or double quote was not closed.
+    #[display(fmt = "unterminated quote")]
+    UnterminatedQuote,
+    /// A redirection is not followed by the file name, as in `a >`.
+    #[display(fmt = "missing redirection target")]
+    MissingRedirectTarget,
+    /// A redirection is not supported, as in `a >&2`.
+    #[display(fmt = "invalid redirection")]
+    InvalidRedirect,
+    /// A variable without a default value is not set, as in `echo $FOO`
+    /// when `FOO` is not defined.
+    #[display(fmt = "unset variable: {}", _0)]
+    UnsetVariable(#[error(not(source))] String),
+    /// A variable expansion is malformed, as in `${FOO` or `${FOO-bar}`.
+    #[display(fmt = "invalid variable expansion")]
+    InvalidVariable,
+}
+
+/// Source of the variables expanded in the command line string.
+#[derive(Debug, Clone)]
+pub(crate) enum Vars {
+    /// The environment of the current process.
+    Process,
+    /// A supplied set of variables.
+    Map(HashMap<String, String>),
+}
+
+impl Vars {
+    fn get(&self, name: &str) -> Option<String> {
+        match self {
+            Vars::Process => env::var(name).ok(),
+            Vars::Map(vars) => vars.get(name).cloned(),
+        }
+    }
+}
+
+/// Redirection of one of the standard streams of a stage.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Redirect {
+    /// `< file`
+    Stdin(String),
+    /// `> file` or `>> file`
+    Stdout { path: String, append: bool },
+    /// `2> file` or `2>> file`
+    Stderr { path: String, append: bool },
+    /// `2>&1`
+    StderrToStdout,
+}
+
+/// A parsed pipeline stage.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Stage {
+    pub(crate) args: Vec<String>,
+    pub(crate) redirects: Vec<Redirect>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Pipe,
+    Stdin,
+    Stdout { append: bool },
+    Stderr { append: bool },
+    StderrToStdout,
+}
+
+/// Parses the command line string into the argv and redirections of
+/// each pipeline stage.
+///
+/// Words are split at unquoted whitespace and unquoted `|` splits the
+/// stages. Single and double quoted strings are supported as well as
+/// backslash escapes, including the `\n`, `\t` and `\r` control
+/// characters. Unquoted `<`, `>`, `>>`, `2>`, `2>>` and `2>&1`
+/// redirect the standard streams of the stage.
+///
+/// When `vars` is given, `$VAR`, `${VAR}` and `${VAR:-default}` are
+/// expanded outside of single quotes. The expanded values are taken
+/// literally and are not split in words. The default is parsed as a
+/// word, handling quotes and escapes, but variables in it are not
+/// expanded.
+pub(crate) fn parse(cmd: &str, vars: Option<&Vars>) -> Result<Vec<Stage>, ParseError> {
+    let mut stages = vec![Stage::default()];
+    let mut tokens = tokenize(cmd, vars)?.into_iter();
+    while let Some(token) = tokens.next() {
+        let stage = stages.last_mut().unwrap();
+        let mut target = || match tokens.next() {
+            Some(Token::Word(path)) => Ok(path),
+            _ => Err(ParseError::MissingRedirectTarget),
+        };
+        match token {
+            Token::Word(w) => stage.args.push(w),
+            Token::Pipe => stages.push(Stage::default()),
+            Token::Stdin => stage.redirects.push(Redirect::Stdin(target()?)),
+            Token::Stdout { append } => stage.redirects.push(Redirect::Stdout {
+                path: target()?,
+                append,
+            }),
+            Token::Stderr { append } => stage.redirects.push(Redirect::Stderr {
+                path: target()?,
+                append,
+            }),
+            Token::StderrToStdout => stage.redirects.push(Redirect::StderrToStdout),
+        }
+    }
+
+    if stages.len() == 1 && stages[0].args.is_empty() {
+        return Err(ParseError::EmptyCommand);
+    }
+    if stages.iter().any(|s| s.args.is_empty()) {
+        return Err(ParseError::EmptyStage);
+    }
+
+    Ok(stages)
+}
+
+fn tokenize(cmd: &str, vars: Option<&Vars>) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = Vec::new();
+    // `None` when not in the middle of a word, so empty quoted strings
+    // still produce a word
+    let mut word: Option<String> = None;
+    // whether the current word has no quoted or escaped characters, so
+    // a `2` can be taken as the file descriptor of a redirection
+    let mut literal = true;
+    let mut chars = cmd.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' => {
+                if let Some(w) = word.take() {
+                    tokens.push(Token::Word(w));
+                }
+                literal = true;
+            }
+            '|' => {
+                if let Some(w) = word.take() {
+                    tokens.push(Token::Word(w));
+                }
+                literal = true;
+                tokens.push(Token::Pipe);
+            }
+            '<' | '>' => {
+                let stderr = literal && c == '>' && word.as_deref() == Some("2");
+                if stderr {
+                    word = None;
+                } else if let Some(w) = word.take() {
+                    tokens.push(Token::Word(w));
+                }
+                literal = true;
+                tokens.push(redirection(&mut chars, c, stderr)?);
+            }
+            '\\' => {
+                let w = word.get_or_insert_with(String::new);
+                match chars.next() {
+                    Some(c) => w.push(control(c).unwrap_or(c)),
+                    None => w.push('\\'),
+                }
+                literal = false;
+            }
+            '\'' => {
+                single_quoted(&mut chars, word.get_or_insert_with(String::new))?;
+                literal = false;
+            }
+            '"' => {
+                double_quoted(&mut chars, word.get_or_insert_with(String::new), vars)?;
+                literal = false;
+            }
+            '$' if vars.is_some() => match variable(&mut chars, vars.unwrap(), false)? {
+                // an empty value does not produce a word on its own
+                Some(value) => {
+                    if !value.is_empty() {
+                        word.get_or_insert_with(String::new).push_str(&value);
+                    }
+                    literal = false;
+                }
+                None => word.get_or_insert_with(String::new).push('$'),
+            },
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if let Some(w) = word {
+        tokens.push(Token::Word(w));
+    }
+
+    Ok(tokens)
+}
+
+/// Parses the remaining of a redirection operator starting with `c`.
+fn redirection(
+    chars: &mut Peekable<Chars<'_>>,
+    c: char,
+    stderr: bool,
+) -> Result<Token, ParseError> {
+    if c == '<' {
+        return Ok(Token::Stdin);
+    }
+
+    let append = chars.next_if_eq(&'>').is_some();
+    if !append && chars.next_if_eq(&'&').is_some() {
+        return match chars.next() {
+            Some('1') if stderr => Ok(Token::StderrToStdout),
+            _ => Err(ParseError::InvalidRedirect),
+        };
+    }
+
+    Ok(if stderr {
+        Token::Stderr { append }
+    } else {
+        Token::Stdout { append }
+    })
+}
+
+fn single_quoted(chars: &mut Peekable<Chars<'_>>, w: &mut String) -> Result<(), ParseError> {
+    loop {
+        match chars.next().ok_or(ParseError::UnterminatedQuote)? {
+            '\'' => return Ok(()),
+            '\\' => match chars.next().ok_or(ParseError::UnterminatedQuote)? {
+                c @ '\'' | c @ '\\' => w.push(c),
+                c => {
+                    w.push('\\');
+                    w.push(c);
+                }
+            },
+            c => w.push(c),
+        }
+    }
+}
+
+fn double_quoted(
+    chars: &mut Peekable<Chars<'_>>,
+    w: &mut String,
+    vars: Option<&Vars>,
+) -> Result<(), ParseError> {
+    loop {
+        match chars.next().ok_or(ParseError::UnterminatedQuote)? {
+            '"' => return Ok(()),
+            '$' if vars.is_some() => match variable(chars, vars.unwrap(), true)? {
+                Some(value) => w.push_str(&value),
+                None => w.push('$'),
+            },
+            '\\' => match chars.next().ok_or(ParseError::UnterminatedQuote)? {
+                c @ '"' | c @ '\'' | c @ '\\' => w.push(c),
+                '$' if vars.is_some() => w.push('$'),
+                c => match control(c) {
+                    Some(c) => w.push(c),
+                    None => {
+                        w.push('\\');
+                        w.push(c);
+                    }
+                },
+            },
+            c => w.push(c),
+        }
+    }
+}
+
+/// Expands the variable following a `$`, returning `None` when it is
+/// not followed by a variable name and must be taken literally.
+/// `quoted` tells whether it is inside double quotes.
+fn variable(
+    chars: &mut Peekable<Chars<'_>>,
+    vars: &Vars,
+    quoted: bool,
+) -> Result<Option<String>, ParseError> {
+    let is_name = |c: char, first: bool| {
+        c == '_' || c.is_ascii_alphabetic() || (!first && c.is_ascii_digit())
+    };
+
+    if chars.next_if_eq(&'{').is_none() {
+        let mut name = String::new();
+        while let Some(c) = chars.next_if(|&c| is_name(c, name.is_empty())) {
+            name.push(c);
+        }
+        return match name.is_empty() {
+            true => Ok(None),
+            false => lookup(vars, name, None).map(Some),
+        };
+    }
+
+    let mut name = String::new();
+    loop {
+        match chars.next().ok_or(ParseError::InvalidVariable)? {
+            '}' if !name.is_empty() => return lookup(vars, name, None).map(Some),
+            ':' if !name.is_empty() && chars.next_if_eq(&'-').is_some() => break,
+            c if is_name(c, name.is_empty()) => name.push(c),
+            _ => return Err(ParseError::InvalidVariable),
+        }
+    }
+
+    // the default is parsed as a word, without expanding variables,
+    // and single quotes are taken literally inside double quotes
+    let mut default = String::new();
+    loop {
+        match chars.next().ok_or(ParseError::InvalidVariable)? {
+            '}' => return lookup(vars, name, Some(default)).map(Some),
+            '\\' => {
+                let c = chars.next().ok_or(ParseError::InvalidVariable)?;
+                default.push(control(c).unwrap_or(c));
+            }
+            '\'' if !quoted => single_quoted(chars, &mut default)?,
+            '"' => double_quoted(chars, &mut default, None)?,
+            c => default.push(c),
+        }
+    }
+}
+
+/// Looks the variable up, using the default when it is unset or
+/// empty.
+fn lookup(vars: &Vars, name: String, default: Option<String>) -> Result<String, ParseError> {
+    match (vars.get(&name), default) {
+        (Some(value), Some(default)) if value.is_empty() => Ok(default),
+        (Some(value), _) => Ok(value),
+        (None, Some(default)) => Ok(default),
+        (None, None) => Err(ParseError::UnsetVariable(name)),
+    }
+}
+
+/// Quotes the argv into a command line string which is parsed back
+/// into the same argv by `easy_process::run`.
+///
+/// Words made only of ASCII letters, digits and `-_./:,+@%` are kept
+/// as they are, the others are put in single quotes, with single quotes
+/// and backslashes escaped outside of them. Words containing `=` are
+/// quoted as well, so shells do not take them as variable assignments.
+/// The result is also valid for POSIX shells, so it can be used to log
+/// commands in a form which can be copied to a terminal.
+///
+/// # Example
+/// ```
+/// let cmd = easy_process::quote(&["git", "commit", "-m", "it's done"]);
+/// assert_eq!(cmd, r#"git commit -m 'it'\''s done'"#);
+/// ```
+pub fn quote<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter()
+        .map(|a| quote_word(a.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn quote_word(word: &str) -> String {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "-_./:,+@%".contains(c);
+    if !word.is_empty() && word.chars().all(safe) {
+        return word.to_string();
+    }
+
+    let mut quoted = String::from("'");
+    for c in word.chars() {
+        match c {
+            '\'' | '\\' => {
+                quoted.push('\'');
+                quoted.push('\\');
+                quoted.push(c);
+                quoted.push('\'');
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('\'');
+
+    quoted
+}
+
+fn control(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        'r' => Some('\r'),
+        't' => Some('\t'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(cmd: &str) -> Vec<String> {
+        let mut stages = parse(cmd, None).unwrap();
+        assert_eq!(stages.len(), 1);
+        stages.remove(0).args
+    }
+
+    fn redirects(cmd: &str) -> Vec<Redirect> {
+        let mut stages = parse(cmd, None).unwrap();
+        assert_eq!(stages.len(), 1);
+        stages.remove(0).redirects
+    }
+
+    #[test]
+    fn posix_words() {
+        assert_eq!(words("Hello world"), ["Hello", "world"]);
+        assert_eq!(words("Hello\\ world"), ["Hello world"]);
+        assert_eq!(
+            words(r##"Hello world "double quoted (\")" '"single quoted (\')"'  escaped\ string"##),
+            [
+                "Hello",
+                "world",
+                "double quoted (\")",
+                "\"single quoted (')\"",
+                "escaped string"
+            ]
+        );
+        assert_eq!(words(r#"  a  '' "\n" b\tc "#), ["a", "", "\n", "b\tc"]);
+    }
+
+    #[test]
+    fn pipelines() {
+        let args = parse("dmesg | grep usb|wc -l", None)
+            .unwrap()
+            .into_iter()
+            .map(|s| s.args)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            vec![vec!["dmesg"], vec!["grep", "usb"], vec!["wc", "-l"]]
+        );
+        assert_eq!(words(r#"echo '|' "|" \|"#), ["echo", "|", "|", "|"]);
+    }
+
+    #[test]
+    fn redirections() {
+        let stdout = |path: &str, append| Redirect::Stdout {
+            path: path.to_string(),
+            append,
+        };
+        let stderr = |path: &str, append| Redirect::Stderr {
+            path: path.to_string(),
+            append,
+        };
+
+        assert_eq!(
+            redirects("cat < in > out 2> err"),
+            [
+                Redirect::Stdin("in".to_string()),
+                stdout("out", false),
+                stderr("err", false)
+            ]
+        );
+        assert_eq!(
+            redirects("cat >>out 2>>err 2>&1"),
+            [
+                stdout("out", true),
+                stderr("err", true),
+                Redirect::StderrToStdout
+            ]
+        );
+        assert_eq!(words("echo a2>out"), ["echo", "a2"]);
+        assert_eq!(redirects("echo a2>out"), [stdout("out", false)]);
+        assert_eq!(
+            words(r#"echo '2'>out "a > b" \>"#),
+            ["echo", "2", "a > b", ">"]
+        );
+    }
+
+    #[test]
+    fn variables() {
+        let vars = Vars::Map(
+            [("FOO", "foo bar"), ("EMPTY", "")]
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        );
+        let words = |cmd| parse(cmd, Some(&vars)).map(|mut s| s.remove(0).args);
+
+        assert_eq!(
+            words(r#"echo $FOO "${FOO}!" '$FOO' \$FOO"#).unwrap(),
+            ["echo", "foo bar", "foo bar!", "$FOO", "$FOO"]
+        );
+        assert_eq!(
+            words(r#"echo ${BAR:-a b} "${EMPTY:-c}" $EMPTY "$EMPTY""#).unwrap(),
+            ["echo", "a b", "c", ""]
+        );
+        assert_eq!(
+            words(r#"echo ${BAR:-"a b"} ${BAR:-'c'\}d} "${BAR:-"e"'f'}" ${BAR:-$FOO}"#).unwrap(),
+            ["echo", "a b", "c}d", "e'f'", "$FOO"]
+        );
+        assert_eq!(
+            words(r#"echo ${BAR:-"a}"#),
+            Err(ParseError::UnterminatedQuote)
+        );
+        assert_eq!(words("echo $ $1 a$").unwrap(), ["echo", "$", "$1", "a$"]);
+        assert_eq!(
+            words("echo $BAR"),
+            Err(ParseError::UnsetVariable("BAR".to_string()))
+        );
+        assert_eq!(words("echo ${FOO"), Err(ParseError::InvalidVariable));
+        assert_eq!(words("echo ${FOO-a}"), Err(ParseError::InvalidVariable));
+        assert_eq!(words("echo ${}"), Err(ParseError::InvalidVariable));
+        assert_eq!(parse("echo $BAR", None).unwrap()[0].args, ["echo", "$BAR"]);
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(quote(&["ls", "-l", "/tmp/a_b.c"]), "ls -l /tmp/a_b.c");
+        assert_eq!(
+            quote(&["echo", "a b", "", "$HOME", "a|b", "2>"]),
+            "echo 'a b' '' '$HOME' 'a|b' '2>'"
+        );
+        assert_eq!(quote(&[r"a\b", "'"]), r"'a'\\'b' ''\'''");
+        assert_eq!(quote(&["FOO=bar", "--a=b"]), "'FOO=bar' '--a=b'");
+        assert_eq!(quote::<&str>(&[]), "");
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn quote_round_trip(args in proptest::collection::vec(".*", 1..6)) {
+            let quoted = quote(&args);
+            let vars = Vars::Map(HashMap::new());
+            for vars in [None, Some(&vars)] {
+                let mut stages = parse(&quoted, vars).unwrap();
+                proptest::prop_assert_eq!(stages.len(), 1);
+                proptest::prop_assert_eq!(&stages.remove(0).args, &args);
+            }
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse(" ", None), Err(ParseError::EmptyCommand));
+        assert_eq!(parse("a | | b", None), Err(ParseError::EmptyStage));
+        assert_eq!(parse("a |", None), Err(ParseError::EmptyStage));
+        assert_eq!(parse("echo 'a", None), Err(ParseError::UnterminatedQuote));
+        assert_eq!(
+            parse(r#"echo "a\""#, None),
+            Err(ParseError::UnterminatedQuote)
+        );
+        assert_eq!(
+            parse("echo >", None),
+            Err(ParseError::MissingRedirectTarget)
+        );
+        assert_eq!(
+            parse("echo > | cat", None),
+            Err(ParseError::MissingRedirectTarget)
+        );
+        assert_eq!(parse("echo >&2", None), Err(ParseError::InvalidRedirect));
+        assert_eq!(parse("> out", None), Err(ParseError::EmptyCommand));
+    }
+}
@@ -0,0 +1,140 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{Command, Error, Output, Result};
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// Runs many independent commands concurrently, with at most a given
+/// number of them running at once.
+///
+/// # Example
+/// ```no_run
+/// let results = easy_process::Pool::new(8)
+///     .fail_fast(true)
+///     .run((0..100).map(|i| format!("ping -c 1 10.0.0.{}", i)));
+/// for result in results {
+///     println!("{:?}", result);
+/// }
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Pool {
+    max_concurrency: usize,
+    fail_fast: bool,
+}
+
+impl Pool {
+    /// Constructs a new `Pool` running at most `max_concurrency`
+    /// commands at once.
+    pub fn new(max_concurrency: usize) -> Pool {
+        Pool {
+            max_concurrency: max_concurrency.max(1),
+            fail_fast: false,
+        }
+    }
+
+    /// When enabled, the first command exiting with a non successful
+    /// status makes the running commands to be killed and the
+    /// remaining ones to be skipped, all of them returning
+    /// `Error::Cancelled`.
+    pub fn fail_fast(&mut self, fail_fast: bool) -> &mut Pool {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Runs the given command line strings, as done by
+    /// `easy_process::run`, returning their results in the same order.
+    pub fn run<I, S>(&self, cmds: I) -> Vec<Result<Output>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let cmds = cmds
+            .into_iter()
+            .map(|c| c.as_ref().to_string())
+            .collect::<Vec<_>>();
+        let next = AtomicUsize::new(0);
+        let cancel = Arc::new(AtomicBool::new(false));
+
+        let mut results = thread::scope(|s| {
+            let workers = (0 .. self.max_concurrency.min(cmds.len()))
+                .map(|_| s.spawn(|| self.worker(&cmds, &next, &cancel)))
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .flat_map(|w| w.join().expect("pool worker panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, r)| r).collect()
+    }
+
+    /// Takes the next command to be run until all of them are taken,
+    /// returning the results along with the command indexes.
+    fn worker(
+        &self,
+        cmds: &[String],
+        next: &AtomicUsize,
+        cancel: &Arc<AtomicBool>,
+    ) -> Vec<(usize, Result<Output>)> {
+        let mut results = Vec::new();
+        loop {
+            let i = next.fetch_add(1, Ordering::SeqCst);
+            let cmd = match cmds.get(i) {
+                Some(cmd) => cmd,
+                None => return results,
+            };
+            if !self.fail_fast {
+                results.push((i, Command::new(cmd).run()));
+                continue;
+            }
+
+            let result = match cancel.load(Ordering::SeqCst) {
+                true => Err(Error::Cancelled),
+                false => Command::new(cmd).cancel_on(cancel.clone()).run(),
+            };
+            if let Err(Error::Failure(..) | Error::PipelineFailure(..)) = result {
+                cancel.store(true, Ordering::SeqCst);
+            }
+            results.push((i, result));
+        }
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn input_order() {
+        let results = Pool::new(3).run(["sh -c 'sleep 0.2; echo a'", "echo b", "false", "echo c"]);
+        assert_eq!(results.len(), 4);
+        assert_eq!(&results[0].as_ref().unwrap().stdout, "a\n");
+        assert_eq!(&results[1].as_ref().unwrap().stdout, "b\n");
+        assert!(matches!(results[2], Err(Error::Failure(..))));
+        assert_eq!(&results[3].as_ref().unwrap().stdout, "c\n");
+
+        assert!(Pool::new(2).run(Vec::<String>::new()).is_empty());
+    }
+
+    #[test]
+    fn fail_fast() {
+        let start = Instant::now();
+        let results =
+            Pool::new(2)
+                .fail_fast(true)
+                .run(["sleep 10", "sh -c 'sleep 0.1; false'", "echo a"]);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(matches!(results[0], Err(Error::Cancelled)));
+        assert!(matches!(results[1], Err(Error::Failure(..))));
+        assert!(matches!(results[2], Err(Error::Cancelled)));
+    }
+}
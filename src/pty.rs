@@ -0,0 +1,69 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::Stage;
+use nix::{
+    libc,
+    pty::{openpty, Winsize},
+};
+use std::{
+    fs::File,
+    io::{self, Read},
+    os::unix::process::CommandExt,
+    process::{self, Stdio},
+};
+
+/// The master side of a pseudo-terminal, from which everything written
+/// to the terminal is read.
+#[derive(Debug)]
+pub(crate) struct Terminal(File);
+
+impl Read for Terminal {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.0.read(buf) {
+            // Linux fails with EIO once every slave descriptor is closed
+            Err(e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            res => res,
+        }
+    }
+}
+
+/// Allocates a pseudo-terminal with the given window size, attaching
+/// the stdout of the last stage and the stderr of every stage to it.
+///
+/// The terminal becomes the controlling terminal of the last stage, so
+/// it can also be opened through `/dev/tty`.
+pub(crate) fn attach(stages: &mut [Stage], rows: u16, cols: u16) -> io::Result<Terminal> {
+    let size = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let pty = openpty(&size, None)?;
+
+    for stage in stages.iter_mut() {
+        stage.cmd.stderr(Stdio::from(pty.slave.try_clone()?));
+    }
+    let last = stages.last_mut().unwrap();
+    last.cmd.stdout(Stdio::from(pty.slave.try_clone()?));
+    set_controlling_terminal(&mut last.cmd);
+    last.session = true;
+
+    Ok(Terminal(File::from(pty.master)))
+}
+
+#[allow(unsafe_code)]
+fn set_controlling_terminal(cmd: &mut process::Command) {
+    // SAFETY: only async-signal-safe functions are called between the
+    // fork and the exec. Failures are ignored as the terminal is still
+    // usable as stdout, which may also have been redirected.
+    unsafe {
+        cmd.pre_exec(|| {
+            libc::setsid();
+            libc::ioctl(libc::STDOUT_FILENO, libc::TIOCSCTTY, 0);
+            Ok(())
+        });
+    }
+}
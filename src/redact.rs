@@ -0,0 +1,194 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+#[cfg(feature = "regex")]
+use regex::Regex;
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        RwLock, RwLockWriteGuard,
+    },
+};
+
+/// Text replacing the secrets.
+const MASK: &str = "[REDACTED]";
+
+/// The registered secrets, along with the id of their [`Redaction`].
+static SECRETS: RwLock<Vec<(usize, Secret)>> = RwLock::new(Vec::new());
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug)]
+enum Secret {
+    Literal(String),
+    #[cfg(feature = "regex")]
+    Pattern(Regex),
+}
+
+/// A secret registered with [`redact`], which stays registered until
+/// [`Redaction::remove`] is called, even when the handle is dropped.
+#[derive(Debug)]
+// removing the secret consumes the handle, so it is not `Copy`
+#[allow(missing_copy_implementations)]
+pub struct Redaction {
+    id: usize,
+}
+
+impl Redaction {
+    fn register(secret: Option<Secret>) -> Redaction {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        if let Some(secret) = secret {
+            write().push((id, secret));
+        }
+
+        Redaction { id }
+    }
+
+    /// Unregisters the secret, so it is no longer masked.
+    pub fn remove(self) {
+        write().retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Registers a secret for the whole process, so it is masked wherever
+/// the crate shows the commands and their output.
+///
+/// The secrets are replaced by `[REDACTED]` in the `Display` and
+/// `Debug` output of the errors, [`Output`](crate::Output),
+/// [`Invocation`](crate::Invocation) and [`Command`](crate::Command),
+/// which covers the args, environment and captured output of the
+/// commands, as well as in the logged and traced command lines and
+/// output. The output returned to the caller is kept as is. The
+/// returned [`Redaction`] unregisters the secret.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// let token = std::env::var("API_TOKEN").unwrap();
+/// easy_process::redact(token.as_str());
+/// easy_process::Command::from_args(["curl", "-H", &format!("Authorization: {}", token)])
+///     .run()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn redact<S: Into<String>>(secret: S) -> Redaction {
+    let secret = secret.into();
+    Redaction::register(Some(secret).filter(|s| !s.is_empty()).map(Secret::Literal))
+}
+
+/// Registers a pattern matching secrets for the whole process, as done
+/// by [`redact`] for literal secrets. Requires the `regex` feature.
+#[cfg(feature = "regex")]
+pub fn redact_regex(pattern: Regex) -> Redaction {
+    Redaction::register(Some(Secret::Pattern(pattern)))
+}
+
+fn write() -> RwLockWriteGuard<'static, Vec<(usize, Secret)>> {
+    SECRETS.write().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Replaces the registered secrets in the text.
+pub(crate) fn mask(text: &str) -> Cow<'_, str> {
+    let secrets = SECRETS.read().unwrap_or_else(|e| e.into_inner());
+    let mut text = Cow::Borrowed(text);
+    for (_, secret) in secrets.iter() {
+        let masked = match secret {
+            Secret::Literal(s) if text.contains(s.as_str()) => text.replace(s.as_str(), MASK),
+            #[cfg(feature = "regex")]
+            Secret::Pattern(re) => match re.replace_all(&text, MASK) {
+                Cow::Owned(masked) => masked,
+                Cow::Borrowed(_) => continue,
+            },
+            Secret::Literal(_) => continue,
+        };
+        text = Cow::Owned(masked);
+    }
+
+    text
+}
+
+/// Formats the text, or bytes converted to UTF-8, with the registered
+/// secrets masked. `Debug` quotes it as done for strings.
+pub(crate) struct Masked<'a>(pub(crate) Cow<'a, str>);
+
+impl<'a> Masked<'a> {
+    pub(crate) fn bytes(data: &'a [u8]) -> Masked<'a> {
+        Masked(String::from_utf8_lossy(data))
+    }
+}
+
+impl<'a> From<&'a str> for Masked<'a> {
+    fn from(text: &'a str) -> Self {
+        Masked(Cow::Borrowed(text))
+    }
+}
+
+impl fmt::Display for Masked<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&mask(&self.0))
+    }
+}
+
+impl fmt::Debug for Masked<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&mask(&self.0), f)
+    }
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::{Command, Error};
+
+    #[test]
+    fn masked() {
+        redact("s3cr3t-literal");
+        #[cfg(feature = "regex")]
+        redact_regex(Regex::new(r"tok-[0-9]+").unwrap());
+        #[cfg(not(feature = "regex"))]
+        for token in ["tok-123", "tok-42", "tok-7", "tok-1"] {
+            redact(token);
+        }
+
+        assert_eq!(
+            mask("a s3cr3t-literal b tok-123"),
+            "a [REDACTED] b [REDACTED]"
+        );
+        assert!(matches!(mask("nothing here"), Cow::Borrowed(_)));
+
+        let e = Command::new("sh -c 'echo s3cr3t-literal; echo tok-42 >&2; exit 1'")
+            .env("TOKEN", "tok-7")
+            .run()
+            .unwrap_err();
+        let output = match &e {
+            Error::Failure(_, output, _) => output,
+            e => panic!("unexpected error: {:?}", e),
+        };
+        // the output itself is not changed
+        assert_eq!(&output.stdout, "s3cr3t-literal\n");
+        for text in [e.to_string(), format!("{:?}", e)] {
+            assert!(!text.contains("s3cr3t-literal"), "{}", text);
+            assert!(!text.contains("tok-42"), "{}", text);
+            assert!(text.contains("[REDACTED]"), "{}", text);
+        }
+
+        let mut cmd = Command::from_args(["echo", "tok-1"]);
+        cmd.env("TOKEN", "s3cr3t-literal");
+        let text = format!("{:?}", cmd);
+        assert!(!text.contains("tok-1"), "{}", text);
+        assert!(!text.contains("s3cr3t-literal"), "{}", text);
+    }
+
+    #[test]
+    fn removed() {
+        let redaction = redact("short-lived-secret");
+        assert_eq!(mask("short-lived-secret"), "[REDACTED]");
+        redaction.remove();
+        assert_eq!(mask("short-lived-secret"), "short-lived-secret");
+
+        // an empty secret is not registered, but can be removed
+        redact("").remove();
+    }
+}
@@ -0,0 +1,269 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{Command, Error, Output, Result};
+use std::{
+    collections::hash_map::RandomState,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    thread,
+    time::Duration,
+};
+
+/// Delay between the attempts of a [`Retry`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backoff {
+    /// Waits the same time between every attempt.
+    Fixed(Duration),
+    /// Waits `initial` after the first attempt, doubling the delay
+    /// after every following one up to `max`.
+    Exponential {
+        /// Delay after the first attempt
+        initial: Duration,
+        /// Upper bound of the delay
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Backoff::Fixed(delay) => delay,
+            Backoff::Exponential { initial, max } => initial
+                .checked_mul(2u32.saturating_pow(attempt))
+                .map_or(max, |d| d.min(max)),
+        }
+    }
+}
+
+/// Decides whether an error must be retried.
+type Predicate = Box<dyn Fn(&Error) -> bool>;
+
+/// A failed attempt of a [`Retry`], other than the last one.
+#[derive(Debug)]
+pub struct Attempt {
+    /// The error returned by the attempt
+    pub error: Error,
+    /// The time waited before the next attempt
+    pub delay: Duration,
+}
+
+/// Retries commands which fail transiently, waiting between the
+/// attempts.
+///
+/// By default every error but `Error::Parse` is retried; use
+/// [`Retry::retry_if`] to narrow it down, for example to specific exit
+/// codes or stderr contents. Once the attempts are exhausted
+/// `Error::RetriesExhausted` is returned, holding the last error and
+/// the previous attempts.
+///
+/// # Example
+/// ```no_run
+/// # fn run() -> Result<(), easy_process::Error> {
+/// use easy_process::{Backoff, Error, Retry};
+/// use std::time::Duration;
+///
+/// let output = Retry::new(5)
+///     .backoff(Backoff::Exponential {
+///         initial: Duration::from_millis(100),
+///         max: Duration::from_secs(5),
+///     })
+///     .jitter(true)
+///     .retry_if(|e| matches!(e, Error::Failure(_, o, _) if o.stderr.contains("timed out")))
+///     .run("curl -sSf https://example.com")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Retry {
+    max_attempts: u32,
+    backoff: Backoff,
+    jitter: bool,
+    predicate: Option<Predicate>,
+}
+
+impl fmt::Debug for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Retry")
+            .field("max_attempts", &self.max_attempts)
+            .field("backoff", &self.backoff)
+            .field("jitter", &self.jitter)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+impl Retry {
+    /// Constructs a new `Retry` making at most `max_attempts` attempts,
+    /// waiting one second between them.
+    pub fn new(max_attempts: u32) -> Retry {
+        Retry {
+            max_attempts: max_attempts.max(1),
+            backoff: Backoff::Fixed(Duration::from_secs(1)),
+            jitter: false,
+            predicate: None,
+        }
+    }
+
+    /// Sets the delay between the attempts.
+    pub fn backoff(&mut self, backoff: Backoff) -> &mut Retry {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Randomizes every delay between half and the whole of it, so
+    /// concurrent callers do not retry in lockstep.
+    pub fn jitter(&mut self, jitter: bool) -> &mut Retry {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Only retries the errors for which the predicate returns `true`;
+    /// other errors are returned right away.
+    pub fn retry_if<P>(&mut self, predicate: P) -> &mut Retry
+    where
+        P: Fn(&Error) -> bool + 'static,
+    {
+        self.predicate = Some(Box::new(predicate));
+        self
+    }
+
+    /// Runs the given command line string, as done by
+    /// `easy_process::run`, until it succeeds.
+    ///
+    /// # Errors
+    ///
+    /// if an error is not retried or the attempts are exhausted, in
+    /// which case `Error::RetriesExhausted` is returned.
+    pub fn run(&self, cmd: &str) -> Result<Output> {
+        self.call(|| Command::new(cmd).run())
+    }
+
+    /// Calls `f` until it succeeds, allowing any of the crate's
+    /// functions or a configured [`Command`] to be retried.
+    ///
+    /// # Errors
+    ///
+    /// if an error is not retried or the attempts are exhausted, in
+    /// which case `Error::RetriesExhausted` is returned.
+    pub fn call<T, F>(&self, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Result<T>,
+    {
+        let mut attempts = Vec::new();
+        loop {
+            let error = match f() {
+                Ok(v) => return Ok(v),
+                Err(e) => e,
+            };
+            if !self.should_retry(&error) {
+                return Err(error);
+            }
+            if attempts.len() as u32 + 1 >= self.max_attempts {
+                return Err(Error::RetriesExhausted(Box::new(error), attempts));
+            }
+
+            let delay = self.delay(attempts.len() as u32);
+            thread::sleep(delay);
+            attempts.push(Attempt { error, delay });
+        }
+    }
+
+    fn should_retry(&self, error: &Error) -> bool {
+        match &self.predicate {
+            Some(predicate) => predicate(error),
+            None => !matches!(error, Error::Parse(_)),
+        }
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff.delay(attempt);
+        if !self.jitter {
+            return delay;
+        }
+
+        let half = delay / 2;
+        let nanos = (delay - half).as_nanos() as u64;
+        match nanos {
+            0 => delay,
+            n => half + Duration::from_nanos(random() % (n + 1)),
+        }
+    }
+}
+
+/// Returns a random number, seeded by the standard library, which is
+/// good enough for jitter.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::run;
+
+    #[test]
+    fn backoff() {
+        let exp = Backoff::Exponential {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        assert_eq!(exp.delay(0), Duration::from_millis(100));
+        assert_eq!(exp.delay(3), Duration::from_millis(800));
+        assert_eq!(exp.delay(4), Duration::from_secs(1));
+        assert_eq!(exp.delay(100), Duration::from_secs(1));
+
+        let mut retry = Retry::new(3);
+        retry.backoff(exp).jitter(true);
+        for attempt in 0 .. 5 {
+            let delay = retry.delay(attempt);
+            assert!(delay >= exp.delay(attempt) / 2 && delay <= exp.delay(attempt));
+        }
+    }
+
+    #[test]
+    fn succeeds_after_failures() {
+        let mut calls = 0;
+        let output = Retry::new(3)
+            .backoff(Backoff::Fixed(Duration::from_millis(1)))
+            .call(|| {
+                calls += 1;
+                run(if calls < 3 { "false" } else { "echo ok" })
+            })
+            .unwrap();
+        assert_eq!(calls, 3);
+        assert_eq!(&output.stdout, "ok\n");
+    }
+
+    #[test]
+    fn exhausted() {
+        match Retry::new(3)
+            .backoff(Backoff::Fixed(Duration::from_millis(1)))
+            .run("sh -c 'exit 2'")
+        {
+            Err(Error::RetriesExhausted(last, attempts)) => {
+                assert_eq!(attempts.len(), 2);
+                assert!(matches!(*last, Error::Failure(..)));
+                assert_eq!(last.exit(), Some(crate::ExitKind::Exited(2)));
+                assert_eq!(attempts[0].delay, Duration::from_millis(1));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn not_retried() {
+        let mut calls = 0;
+        let err = Retry::new(3)
+            .retry_if(|e| e.exit() == Some(crate::ExitKind::Exited(75)))
+            .call(|| {
+                calls += 1;
+                run("false")
+            })
+            .unwrap_err();
+        assert_eq!(calls, 1);
+        assert!(matches!(err, Error::Failure(..)));
+
+        assert!(matches!(Retry::new(3).run("echo 'a"), Err(Error::Parse(_))));
+    }
+}
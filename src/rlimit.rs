@@ -0,0 +1,90 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+#[cfg(unix)]
+use crate::Stage;
+#[cfg(unix)]
+use std::{io, os::unix::process::CommandExt};
+
+/// Resources of a process which can be limited with
+/// `Command::rlimit` on Unix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rlimit {
+    /// The size of the virtual memory, in bytes (`RLIMIT_AS`).
+    ///
+    /// Exceeding it makes the allocations to fail, so the process
+    /// usually exits on its own rather than being signaled.
+    AddressSpace,
+    /// The CPU time, in seconds (`RLIMIT_CPU`).
+    Cpu,
+    /// The number of open file descriptors (`RLIMIT_NOFILE`).
+    OpenFiles,
+    /// The size of the files written, in bytes (`RLIMIT_FSIZE`).
+    FileSize,
+    /// The size of the core dumps, in bytes (`RLIMIT_CORE`).
+    Core,
+}
+
+impl Rlimit {
+    /// Returns the resource whose limit is reported by the signal, as
+    /// done for the CPU time and file size limits.
+    #[cfg(unix)]
+    pub(crate) fn from_signal(signal: i32) -> Option<Rlimit> {
+        use nix::sys::signal::Signal;
+
+        match signal {
+            s if s == Signal::SIGXCPU as i32 => Some(Rlimit::Cpu),
+            s if s == Signal::SIGXFSZ as i32 => Some(Rlimit::FileSize),
+            _ => None,
+        }
+    }
+}
+
+/// Applies the limits to every stage right before the exec.
+///
+/// The hard limit of the CPU time is a second above the soft one, so
+/// the process receives `SIGXCPU` instead of being killed with
+/// `SIGKILL`, allowing it to be told apart.
+#[cfg(unix)]
+#[allow(unsafe_code)]
+pub(crate) fn apply(stages: &mut [Stage], limits: &[(Rlimit, u64)]) {
+    use nix::sys::resource::{rlim_t, setrlimit, Resource};
+    use std::convert::TryFrom;
+
+    if limits.is_empty() {
+        return;
+    }
+    for stage in stages.iter_mut() {
+        stage.rlimits = limits.iter().map(|&(resource, _)| resource).collect();
+    }
+
+    let limits = limits
+        .iter()
+        .map(|&(resource, limit)| {
+            let (resource, hard) = match resource {
+                Rlimit::AddressSpace => (Resource::RLIMIT_AS, limit),
+                Rlimit::Cpu => (Resource::RLIMIT_CPU, limit.saturating_add(1)),
+                Rlimit::OpenFiles => (Resource::RLIMIT_NOFILE, limit),
+                Rlimit::FileSize => (Resource::RLIMIT_FSIZE, limit),
+                Rlimit::Core => (Resource::RLIMIT_CORE, limit),
+            };
+            let clamp = |l| rlim_t::try_from(l).unwrap_or(rlim_t::MAX);
+            (resource, clamp(limit), clamp(hard))
+        })
+        .collect::<Vec<_>>();
+
+    for stage in stages.iter_mut() {
+        let limits = limits.clone();
+        // SAFETY: setrlimit is async-signal-safe and nothing is
+        // allocated between the fork and the exec.
+        unsafe {
+            stage.cmd.pre_exec(move || {
+                for &(resource, soft, hard) in &limits {
+                    setrlimit(resource, soft, hard).map_err(io::Error::from)?;
+                }
+                Ok(())
+            });
+        }
+    }
+}
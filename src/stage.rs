@@ -0,0 +1,115 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::{parser::Redirect, Rlimit};
+use os_pipe::PipeReader;
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::PathBuf,
+    process,
+};
+
+/// A pipeline stage ready to be spawned.
+#[derive(Debug)]
+pub(crate) struct Stage {
+    pub(crate) cmd: process::Command,
+    pub(crate) redirects: Vec<Redirect>,
+    /// Whether the stage starts a new session, which makes it the
+    /// leader of a new process group as well.
+    #[cfg(unix)]
+    pub(crate) session: bool,
+    /// The resources limited before the exec.
+    pub(crate) rlimits: Vec<Rlimit>,
+}
+
+impl From<process::Command> for Stage {
+    fn from(cmd: process::Command) -> Self {
+        Stage {
+            cmd,
+            redirects: Vec::new(),
+            #[cfg(unix)]
+            session: false,
+            rlimits: Vec::new(),
+        }
+    }
+}
+
+impl Stage {
+    /// Opens the files of the stage redirections, in order, overriding
+    /// the stdio configuration of the process.
+    ///
+    /// When stderr is redirected to stdout, as in `2>&1`, and stdout is
+    /// not a file, both are connected to a new pipe which reading end
+    /// is returned and must be used as the stdout of the stage.
+    pub(crate) fn redirect(&mut self) -> io::Result<Option<PipeReader>> {
+        #[derive(Debug)]
+        enum Target {
+            Unset,
+            File(File),
+            Stdout,
+        }
+
+        let mut stdout = None;
+        let mut stderr = Target::Unset;
+        for redirect in &self.redirects {
+            match redirect {
+                Redirect::Stdin(path) => {
+                    self.cmd.stdin(File::open(self.path(path))?);
+                }
+                Redirect::Stdout { path, append } => {
+                    stdout = Some(self.create(path, *append)?);
+                }
+                Redirect::Stderr { path, append } => {
+                    stderr = Target::File(self.create(path, *append)?);
+                }
+                Redirect::StderrToStdout => {
+                    stderr = match &stdout {
+                        Some(file) => Target::File(file.try_clone()?),
+                        None => Target::Stdout,
+                    };
+                }
+            }
+        }
+
+        let merged = match stderr {
+            Target::Unset => None,
+            Target::File(file) => {
+                self.cmd.stderr(file);
+                None
+            }
+            Target::Stdout => {
+                let (reader, writer) = os_pipe::pipe()?;
+                if stdout.is_none() {
+                    self.cmd.stdout(writer.try_clone()?);
+                }
+                self.cmd.stderr(writer);
+                Some(reader)
+            }
+        };
+        if let Some(file) = stdout {
+            self.cmd.stdout(file);
+        }
+
+        Ok(merged)
+    }
+
+    /// Resolves the path relative to the working directory of the
+    /// process.
+    fn path(&self, path: &str) -> PathBuf {
+        match self.cmd.get_current_dir() {
+            Some(cwd) => cwd.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+
+    fn create(&self, path: &str, append: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(self.path(path))
+    }
+}
@@ -0,0 +1,182 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+use crate::Rlimit;
+use std::{fmt, process::ExitStatus};
+
+/// Classification of how a process terminated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitKind {
+    /// The process exited with the given code.
+    Exited(i32),
+    /// The process was killed by a signal.
+    Signaled {
+        /// The signal number
+        signal: i32,
+        /// The signal name, such as `SIGKILL`, when known
+        name: Option<&'static str>,
+        /// Whether a core dump was produced
+        core_dumped: bool,
+    },
+    /// The process was killed by a signal for exceeding a resource
+    /// limit it had set, such as `SIGXCPU` for the CPU time limit.
+    ResourceLimit {
+        /// The exceeded resource
+        resource: Rlimit,
+        /// The signal number
+        signal: i32,
+    },
+    /// The process terminated in a way not covered above.
+    Unknown,
+}
+
+impl ExitKind {
+    /// Classifies the exit status, reporting the signals sent for
+    /// exceeding a resource limit as `ResourceLimit` only when the
+    /// resource is one of the given limited ones.
+    #[cfg(unix)]
+    pub(crate) fn new(status: ExitStatus, rlimits: &[Rlimit]) -> Self {
+        use nix::sys::signal::Signal;
+        use std::{convert::TryFrom, os::unix::process::ExitStatusExt};
+
+        match (status.code(), status.signal()) {
+            (Some(code), _) => ExitKind::Exited(code),
+            (None, Some(signal)) => match Rlimit::from_signal(signal) {
+                Some(resource) if rlimits.contains(&resource) => {
+                    ExitKind::ResourceLimit { resource, signal }
+                }
+                _ => ExitKind::Signaled {
+                    signal,
+                    name: Signal::try_from(signal).ok().map(Signal::as_str),
+                    core_dumped: status.core_dumped(),
+                },
+            },
+            (None, None) => ExitKind::Unknown,
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub(crate) fn new(status: ExitStatus, _rlimits: &[Rlimit]) -> Self {
+        match status.code() {
+            Some(code) => ExitKind::Exited(code),
+            None => ExitKind::Unknown,
+        }
+    }
+}
+
+/// Classifies the exit status of a process which had no resource
+/// limited, so it is never reported as `ResourceLimit`.
+impl From<ExitStatus> for ExitKind {
+    fn from(status: ExitStatus) -> Self {
+        ExitKind::new(status, &[])
+    }
+}
+
+impl fmt::Display for ExitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitKind::Exited(code) => write!(f, "exited with code {}", code),
+            ExitKind::Signaled {
+                signal,
+                name,
+                core_dumped,
+            } => {
+                write!(f, "killed by signal {}", signal)?;
+                if let Some(name) = name {
+                    write!(f, " ({})", name)?;
+                }
+                if *core_dumped {
+                    write!(f, " (core dumped)")?;
+                }
+                Ok(())
+            }
+            ExitKind::ResourceLimit { resource, signal } => {
+                let name = match resource {
+                    Rlimit::AddressSpace => "address space",
+                    Rlimit::Cpu => "CPU time",
+                    Rlimit::OpenFiles => "open files",
+                    Rlimit::FileSize => "file size",
+                    Rlimit::Core => "core size",
+                };
+                write!(f, "exceeded the {} limit (signal {})", name, signal)
+            }
+            ExitKind::Unknown => write!(f, "terminated for an unknown reason"),
+        }
+    }
+}
+
+/// Returns the raw value of the exit status, so it can be stored.
+#[cfg(unix)]
+pub(crate) fn status_to_raw(status: &ExitStatus) -> i64 {
+    std::os::unix::process::ExitStatusExt::into_raw(*status).into()
+}
+
+/// Rebuilds the exit status from its raw value.
+#[cfg(unix)]
+pub(crate) fn status_from_raw(raw: i64) -> Option<ExitStatus> {
+    use std::convert::TryFrom;
+    i32::try_from(raw)
+        .ok()
+        .map(std::os::unix::process::ExitStatusExt::from_raw)
+}
+
+#[cfg(windows)]
+pub(crate) fn status_to_raw(status: &ExitStatus) -> i64 {
+    status.code().map_or(0, |c| c as u32).into()
+}
+
+#[cfg(windows)]
+pub(crate) fn status_from_raw(raw: i64) -> Option<ExitStatus> {
+    use std::convert::TryFrom;
+    u32::try_from(raw)
+        .ok()
+        .map(std::os::windows::process::ExitStatusExt::from_raw)
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use crate::{run, Error};
+
+    #[test]
+    fn exited() {
+        let e = run("sh -c 'exit 3'").unwrap_err();
+        assert_eq!(e.exit(), Some(ExitKind::Exited(3)));
+        assert!(e.to_string().contains("status: exited with code 3"));
+    }
+
+    #[test]
+    fn signaled() {
+        match run(r#"sh -c 'kill -KILL $$'"#) {
+            Err(e @ Error::Failure(..)) => {
+                assert_eq!(
+                    e.exit(),
+                    Some(ExitKind::Signaled {
+                        signal: 9,
+                        name: Some("SIGKILL"),
+                        core_dumped: false,
+                    })
+                );
+                assert!(e
+                    .to_string()
+                    .contains("status: killed by signal 9 (SIGKILL)"));
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn resource_limit_signal_without_limit() {
+        // only the limits set for the command are reported as exceeded
+        let e = run(r#"sh -c 'kill -XCPU $$'"#).unwrap_err();
+        assert!(matches!(
+            e.exit(),
+            Some(ExitKind::Signaled {
+                name: Some("SIGXCPU"),
+                ..
+            })
+        ));
+        assert!(!e.to_string().contains("exceeded"));
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! Asynchronous counterparts of `easy_process::run` and
+//! `easy_process::run_with_stdin` running on top of [tokio].
+//!
+//! They parse the command line string the same way as their blocking
+//! versions and return `Error::Failure` for non successful exit
+//! statuses.
+//!
+//! [tokio]: https://docs.rs/tokio
+
+use crate::{
+    checked_output, dry_run, fixture, invocations, redirected_stdin, setup_process, trace, Error,
+    Finished, Invocation, Output, RawOutput, Result,
+};
+use ::tokio::{
+    io::AsyncReadExt,
+    process::{Child, ChildStdin, Command},
+};
+use os_pipe::PipeReader;
+use std::{
+    convert::TryInto,
+    future::Future,
+    io::{self, Read},
+    process::Stdio,
+    time::Instant,
+};
+
+/// Runs the given command asynchronously
+///
+/// # Arguments
+///
+/// `cmd` - A string slice containing the command to be run.
+///
+/// # Errors
+///
+/// if the exit status is not successful or a `io::Error` was returned.
+pub async fn run(cmd: &str) -> Result<Output> {
+    if let Some(output) = dry_run::global() {
+        return checked_output(dry_run::finish(&setup_process(cmd, None)?, output));
+    }
+    let running = spawn(cmd, Stdio::null(), Stdio::piped)?;
+
+    collect(running).await
+}
+
+/// Runs command asynchronously with access to it's stdin.
+///
+/// Spawns the given command then hands it's piped stdin, which
+/// implements `AsyncWrite`, to the given async closure. The stdin is
+/// closed once the closure's future completes. The closure's Result
+/// Error type is used as the function's result so the users can use
+/// their locally defined error types and [`enum@Error`] itself can
+/// also be used.
+///
+/// As done by `easy_process::run_with_stdin`, stderr is inherited
+/// from the current process so it is left empty in the output.
+///
+/// # Examples
+/// ```no_run
+/// # async fn run() -> Result<(), easy_process::Error> {
+/// use tokio::io::AsyncWriteExt;
+///
+/// let output = easy_process::tokio::run_with_stdin("rev", |mut stdin| async move {
+///     stdin.write_all(b"Hello, world!").await?;
+///     easy_process::Result::Ok(())
+/// })
+/// .await?;
+/// assert_eq!("!dlrow ,olleH", &output.stdout);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn run_with_stdin<F, Fut, E>(cmd: &str, f: F) -> std::result::Result<Output, E>
+where
+    F: FnOnce(ChildStdin) -> Fut,
+    Fut: Future<Output = std::result::Result<(), E>>,
+    E: From<Error>,
+{
+    if let Some(output) = dry_run::global() {
+        let stages = setup_process(cmd, None)?;
+        // the written data is discarded as nothing is spawned
+        let (mut reader, writer) = os_pipe::pipe().map_err(Error::from)?;
+        let drain = ::tokio::task::spawn_blocking(move || io::copy(&mut reader, &mut io::sink()));
+        let stdin = ChildStdin::from_std(fixture::child_stdin(writer)).map_err(Error::from)?;
+        f(stdin).await?;
+        drain
+            .await
+            .map_err(io::Error::from)
+            .and_then(|r| r)
+            .map_err(Error::from)?;
+
+        return Ok(checked_output(dry_run::finish(&stages, output))?);
+    }
+    let mut running = spawn(cmd, Stdio::piped(), Stdio::inherit)?;
+    // the children are killed when dropped
+    let stdin = running.children[0]
+        .stdin
+        .take()
+        .ok_or_else(|| Error::from(redirected_stdin()))?;
+
+    f(stdin).await?;
+
+    Ok(collect(running).await?)
+}
+
+/// The running stages of a pipeline.
+struct Running {
+    children: Vec<Child>,
+    /// The pipe shared by stdout and stderr of the last stage, when
+    /// they are merged with `2>&1`.
+    merged: Option<PipeReader>,
+    invocations: Vec<Invocation>,
+    start: Instant,
+    span: trace::Span,
+}
+
+/// Spawns every pipeline stage, connecting the stdout of each stage
+/// to the stdin of the next one and setting the stderr of every stage
+/// with `stderr`.
+fn spawn(cmd: &str, stdin: Stdio, stderr: fn() -> Stdio) -> Result<Running> {
+    let stages = setup_process(cmd, None)?;
+    let invocations = invocations(&stages);
+    let span = trace::Span::new(&invocations);
+    let start = Instant::now();
+    let mut children: Vec<Child> = Vec::new();
+    let mut merged: Option<PipeReader> = None;
+    let mut stdin = Some(stdin);
+    for mut stage in stages {
+        let input: Stdio = match (children.last_mut(), merged.take()) {
+            (None, _) => stdin.take().unwrap(),
+            (Some(_), Some(reader)) => reader.into(),
+            (Some(prev), None) => match prev.stdout.take() {
+                Some(stdout) => stdout.try_into()?,
+                None => Stdio::null(),
+            },
+        };
+        stage
+            .cmd
+            .stdin(input)
+            .stdout(Stdio::piped())
+            .stderr(stderr());
+        merged = stage.redirect()?;
+
+        let mut cmd = Command::from(stage.cmd);
+        cmd.kill_on_drop(true);
+        children.push(cmd.spawn()?);
+    }
+    span.spawned(children.iter().filter_map(Child::id));
+
+    Ok(Running {
+        children,
+        merged,
+        invocations,
+        start,
+        span,
+    })
+}
+
+/// Waits for every pipeline stage, collecting the stdout of the last
+/// one and the stderr of all of them.
+async fn collect(mut running: Running) -> Result<Output> {
+    let last = running.children.pop().unwrap();
+    // the stages are waited concurrently so no stage blocks on a full
+    // stderr pipe
+    let stages = running
+        .children
+        .into_iter()
+        .map(|mut child| {
+            ::tokio::spawn(async move {
+                let mut stderr = Vec::new();
+                if let Some(mut pipe) = child.stderr.take() {
+                    pipe.read_to_end(&mut stderr).await?;
+                }
+                Ok::<_, io::Error>((child.wait().await?, stderr))
+            })
+        })
+        .collect::<Vec<_>>();
+    let merged = running.merged.map(|mut reader| {
+        ::tokio::task::spawn_blocking(move || {
+            let mut data = Vec::new();
+            reader.read_to_end(&mut data)?;
+            Ok::<_, io::Error>(data)
+        })
+    });
+    let mut o = last.wait_with_output().await?;
+    if let Some(merged) = merged {
+        o.stdout = merged.await.map_err(io::Error::from)??;
+    }
+
+    let (mut statuses, mut stderr) = (Vec::new(), Vec::new());
+    for stage in stages {
+        let (status, data) = stage.await.map_err(io::Error::from)??;
+        statuses.push(status);
+        stderr.extend(data);
+    }
+    statuses.push(o.status);
+    stderr.extend(o.stderr);
+
+    let finished = Finished {
+        statuses,
+        output: RawOutput {
+            stdout: o.stdout,
+            stderr,
+            ..RawOutput::default()
+        },
+        invocations: running.invocations,
+        duration: running.start.elapsed(),
+    };
+    running.span.finish(Ok(&finished));
+
+    checked_output(finished)
+}
+
+#[cfg(all(test, not(windows)))]
+mod tests {
+    use super::*;
+    use ::tokio::io::AsyncWriteExt;
+
+    #[::tokio::test]
+    async fn failing_command() {
+        match run(r#"sh -c 'echo "error" >&2; exit 1'"#).await {
+            Err(Error::Failure(ex, output, _)) => {
+                assert_eq!(ex.code().unwrap(), 1);
+                assert_eq!(&output.stderr, "error\n");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[::tokio::test]
+    async fn piped_input() {
+        let output = run_with_stdin("rev", |mut stdin| async move {
+            stdin.write_all(b"Hello, world!").await?;
+            Result::Ok(())
+        })
+        .await
+        .unwrap();
+        assert!(&output.stdout.starts_with("!dlrow ,olleH"));
+
+        match run_with_stdin("cat < /dev/null", |_| async { Result::Ok(()) }).await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[::tokio::test]
+    async fn inherited_stderr() {
+        let stdin = |_| async { Result::Ok(()) };
+        match run_with_stdin("sh -c 'echo error >&2; exit 1'", stdin).await {
+            Err(Error::Failure(_, output, _)) => assert_eq!(&output.stderr, ""),
+            r => panic!("unexpected result: {:?}", r),
+        }
+    }
+
+    #[::tokio::test]
+    async fn pipeline_failure() {
+        match run(r#"sh -c 'echo error >&2; exit 3' | cat"#).await {
+            Err(Error::PipelineFailure(stage, ex, output, _)) => {
+                assert_eq!(stage, 0);
+                assert_eq!(ex.code(), Some(3));
+                assert_eq!(&output.stderr, "error\n");
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+
+        let output = run("echo hello | tr a-z A-Z").await.unwrap();
+        assert_eq!(&output.stdout, "HELLO\n");
+
+        let output = run("sh -c 'echo err >&2' 2>&1").await.unwrap();
+        assert_eq!(&output.stdout, "err\n");
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! `tracing` instrumentation of the executed commands, compiled to
+//! no-ops unless the `tracing` feature is enabled.
+
+#[cfg(feature = "tracing")]
+use crate::{command_line, redact::mask};
+use crate::{Error, Finished, Invocation};
+
+/// Span covering the execution of a command, from its spawn until its
+/// exit.
+#[derive(Debug)]
+pub(crate) struct Span {
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+#[cfg(feature = "tracing")]
+impl Span {
+    pub(crate) fn new(invocations: &[Invocation]) -> Span {
+        use tracing::field::{debug, Empty};
+
+        let span = tracing::info_span!(
+            "command",
+            argv = %command_line(invocations),
+            cwd = Empty,
+            pid = Empty,
+            duration = Empty,
+            status = Empty,
+            error = Empty,
+        );
+        if let Some(cwd) = invocations.first().and_then(|i| i.cwd.as_ref()) {
+            span.record("cwd", debug(mask(&cwd.to_string_lossy())));
+        }
+
+        Span { span }
+    }
+
+    /// Records the process identifiers of every stage.
+    pub(crate) fn spawned<I: IntoIterator<Item = u32>>(&self, pids: I) {
+        let pids = pids
+            .into_iter()
+            .map(|p| p.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.span.record("pid", pids.as_str());
+    }
+
+    pub(crate) fn in_scope<F: FnOnce() -> R, R>(&self, f: F) -> R {
+        self.span.in_scope(f)
+    }
+
+    /// Records how the command finished, reporting the last stage which
+    /// failed for pipelines.
+    pub(crate) fn finish(&self, res: Result<&Finished, &Error>) {
+        use tracing::field::{debug, display};
+
+        match res {
+            Ok(f) => {
+                let i = f
+                    .statuses
+                    .iter()
+                    .rposition(|s| !s.success())
+                    .or_else(|| f.statuses.len().checked_sub(1));
+                if let Some(i) = i {
+                    self.span
+                        .record("status", display(f.invocations[i].exit(f.statuses[i])));
+                }
+                self.span.record("duration", debug(f.duration));
+            }
+            Err(e) => {
+                if let Some(i) = e.invocation() {
+                    self.span.record("duration", debug(i.duration));
+                }
+                self.span.record("error", display(e));
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn new(_invocations: &[Invocation]) -> Span {
+        Span {}
+    }
+
+    pub(crate) fn spawned<I: IntoIterator<Item = u32>>(&self, _pids: I) {}
+
+    pub(crate) fn in_scope<F: FnOnce() -> R, R>(&self, f: F) -> R {
+        f()
+    }
+
+    pub(crate) fn finish(&self, _res: Result<&Finished, &Error>) {}
+}
+
+/// Emits an event for a line written by the command.
+#[cfg(feature = "tracing")]
+pub(crate) fn line(stream: &'static str, line: &str) {
+    tracing::debug!(stream, "{}", mask(line));
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn line(_stream: &'static str, _line: &str) {}
+
+#[cfg(all(test, not(windows), feature = "tracing"))]
+mod tests {
+    use crate::Command;
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+    use tracing_subscriber::fmt::{format::FmtSpan, MakeWriter};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl MakeWriter<'_> for Buffer {
+        type Writer = Buffer;
+
+        fn make_writer(&self) -> Buffer {
+            self.clone()
+        }
+    }
+
+    fn traced<F: FnOnce()>(f: F) -> String {
+        let buffer = Buffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_span_events(FmtSpan::CLOSE)
+            .with_ansi(false)
+            .with_writer(buffer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, f);
+
+        let output = buffer.0.lock().unwrap().clone();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn span() {
+        let output = traced(|| {
+            Command::new("sh -c 'echo hello; exit 3'")
+                .cwd("/")
+                .trace_lines(true)
+                .run()
+                .unwrap_err();
+        });
+        let mut lines = output.lines();
+        let line = lines.next().unwrap();
+        assert!(line.contains(r#"command{argv="sh" "-c" "echo hello; exit 3" cwd="/" pid="#));
+        assert!(line.contains(r#"hello stream="stdout""#));
+        let close = lines.next().unwrap();
+        assert!(close.contains("status=exited with code 3 duration="));
+        assert!(close.contains("close"));
+
+        // lines are only traced when asked to
+        let output = traced(|| {
+            Command::new("echo hello").run().unwrap();
+        });
+        assert_eq!(output.lines().count(), 1);
+    }
+}
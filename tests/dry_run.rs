@@ -0,0 +1,102 @@
+// Copyright (C) 2018 O.S. Systems Sofware LTDA
+//
+// SPDX-License-Identifier: MIT OR Apache-2.0
+
+//! The dry-run mode is process wide, so its tests live in their own
+//! binary and are serialized with `LOCK`.
+
+use easy_process::{set_dry_run, Output};
+use std::{
+    io::Write,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+static LOCK: Mutex<()> = Mutex::new(());
+
+/// Enables the dry-run mode until the returned guard is dropped.
+struct DryRun(#[allow(dead_code)] MutexGuard<'static, ()>);
+
+impl DryRun {
+    fn enable(stdout: &str) -> DryRun {
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        set_dry_run(Some(Output {
+            stdout: stdout.to_string(),
+            ..Output::default()
+        }));
+        DryRun(guard)
+    }
+}
+
+impl Drop for DryRun {
+    fn drop(&mut self) {
+        set_dry_run(None);
+    }
+}
+
+fn marker(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "easy-process-dry-run-{}-{}",
+        name,
+        std::process::id()
+    ))
+}
+
+#[test]
+fn run() {
+    let _dry_run = DryRun::enable("dry\n");
+    let path = marker("run");
+    let output = easy_process::run(&format!("touch {}", path.display())).unwrap();
+    assert_eq!(&output.stdout, "dry\n");
+    assert!(!path.exists());
+}
+
+#[test]
+fn run_with_stdin() {
+    let _dry_run = DryRun::enable("dry\n");
+    let path = marker("run-with-stdin");
+    let output = easy_process::run_with_stdin(&format!("tee {}", path.display()), |stdin| {
+        stdin.write_all(b"data")?;
+        easy_process::Result::Ok(())
+    })
+    .unwrap();
+    assert_eq!(&output.stdout, "dry\n");
+    assert!(!path.exists());
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn tokio_run() {
+    let _dry_run = DryRun::enable("dry\n");
+    let path = marker("tokio-run");
+    let output = easy_process::tokio::run(&format!("touch {}", path.display()))
+        .await
+        .unwrap();
+    assert_eq!(&output.stdout, "dry\n");
+    assert!(!path.exists());
+
+    assert!(matches!(
+        easy_process::tokio::run("echo 'a").await,
+        Err(easy_process::Error::Parse(_))
+    ));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn tokio_run_with_stdin() {
+    use tokio::io::AsyncWriteExt;
+
+    let _dry_run = DryRun::enable("dry\n");
+    let path = marker("tokio-run-with-stdin");
+    let output = easy_process::tokio::run_with_stdin(
+        &format!("tee {}", path.display()),
+        |mut stdin| async move {
+            stdin.write_all(b"data").await?;
+            easy_process::Result::Ok(())
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(&output.stdout, "dry\n");
+    assert!(!path.exists());
+}